@@ -0,0 +1,116 @@
+//! 对比每次请求新建 reqwest Client 与复用共享 Client 的延迟
+//!
+//! 在本地启动一个模拟上游（返回一小段事件流），分别用两种方式顺序发送请求并统计延迟。
+//! 运行：`cargo bench --bench upstream_client`
+
+use axum::{routing::post, Router};
+use reqwest::Client;
+use std::net::{SocketAddr, TcpListener};
+use std::time::{Duration, Instant};
+
+const WARMUP: usize = 20;
+const ITERATIONS: usize = 500;
+
+const MOCK_EVENT_STREAM: &str = "data: {\"message\": {\"id\": \"m1\", \"author\": {\"role\": \"assistant\"}, \"content\": {\"parts\": [\"Hello\"]}}}\n\n\
+data: {\"message\": {\"id\": \"m1\", \"author\": {\"role\": \"assistant\"}, \"content\": {\"parts\": [\"Hello! How can I help?\"]}}}\n\n\
+data: [DONE]\n\n";
+
+/// 启动模拟上游，返回监听地址
+fn start_mock_upstream() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("bind mock upstream");
+    let addr = listener.local_addr().unwrap();
+    let app = Router::new().route("/backend-api/conversation", post(|| async { MOCK_EVENT_STREAM }));
+
+    tokio::spawn(async move {
+        axum::Server::from_tcp(listener)
+            .expect("mock upstream listener")
+            .serve(app.into_make_service())
+            .await
+            .expect("mock upstream server");
+    });
+    addr
+}
+
+/// 与 http_client::build_upstream_client 相同的连接池设置
+fn build_client() -> Client {
+    Client::builder()
+        .no_proxy()
+        .connect_timeout(Duration::from_secs(10))
+        .timeout(Duration::from_secs(600))
+        .pool_max_idle_per_host(32)
+        .pool_idle_timeout(Duration::from_secs(90))
+        .tcp_keepalive(Duration::from_secs(60))
+        .build()
+        .expect("build client")
+}
+
+async fn send(client: &Client, url: &str) {
+    let body = client
+        .post(url)
+        .json(&serde_json::json!({ "action": "next" }))
+        .send()
+        .await
+        .expect("send request")
+        .text()
+        .await
+        .expect("read body");
+    assert!(body.ends_with("data: [DONE]\n\n"));
+}
+
+/// 顺序执行请求并返回每次的延迟
+async fn measure<F, Fut>(mut request: F) -> Vec<Duration>
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = ()>,
+{
+    for _ in 0..WARMUP {
+        request().await;
+    }
+
+    let mut samples = Vec::with_capacity(ITERATIONS);
+    for _ in 0..ITERATIONS {
+        let start = Instant::now();
+        request().await;
+        samples.push(start.elapsed());
+    }
+    samples.sort();
+    samples
+}
+
+fn report(name: &str, samples: &[Duration]) -> Duration {
+    let mean = samples.iter().sum::<Duration>() / samples.len() as u32;
+    let percentile = |p: f64| samples[((samples.len() - 1) as f64 * p) as usize];
+    println!(
+        "{:<24} mean {:>10.1?}  p50 {:>10.1?}  p99 {:>10.1?}",
+        name,
+        mean,
+        percentile(0.5),
+        percentile(0.99)
+    );
+    mean
+}
+
+#[tokio::main]
+async fn main() {
+    let addr = start_mock_upstream();
+    let url = format!("http://{}/backend-api/conversation", addr);
+    println!("{} sequential requests against mock upstream at {}\n", ITERATIONS, addr);
+
+    // 旧实现：每个请求都新建客户端，连接池和TLS状态随之丢弃
+    let per_request = measure(|| {
+        let url = url.clone();
+        async move { send(&build_client(), &url).await }
+    })
+    .await;
+
+    // 新实现：启动时创建一次，所有请求共享
+    let shared_client = build_client();
+    let shared = measure(|| send(&shared_client, &url)).await;
+
+    let per_request_mean = report("client per request", &per_request);
+    let shared_mean = report("shared client", &shared);
+    println!(
+        "\nshared client is {:.1}x faster on average",
+        per_request_mean.as_secs_f64() / shared_mean.as_secs_f64()
+    );
+}
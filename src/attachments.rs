@@ -0,0 +1,415 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use reqwest::{header, redirect, Url};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+
+use crate::error::{ProxyError, ProxyResult};
+use crate::http_client::UpstreamClient;
+use crate::openai_types::ImageUrl;
+
+const FILES_URL: &str = "https://chat.openai.com/backend-api/files";
+
+/// 单张图片的大小上限，与官方API一致
+const MAX_IMAGE_BYTES: usize = 20 * 1024 * 1024;
+
+/// 下载图片时最多跟随的重定向次数，每一跳都重新检查目标地址
+const MAX_REDIRECTS: usize = 3;
+
+/// 下载单张图片的连接和总超时
+const DOWNLOAD_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// 已上传到ChatGPT文件服务的图片
+#[derive(Debug, Clone)]
+pub struct ImageAttachment {
+    pub file_id: String,
+    pub name: String,
+    pub mime_type: String,
+    pub size_bytes: usize,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+}
+
+impl ImageAttachment {
+    /// 消息 `parts` 中引用图片的部分
+    pub fn asset_pointer(&self) -> serde_json::Value {
+        serde_json::json!({
+            "content_type": "image_asset_pointer",
+            "asset_pointer": format!("file-service://{}", self.file_id),
+            "size_bytes": self.size_bytes,
+            "width": self.width,
+            "height": self.height,
+        })
+    }
+
+    /// 消息 `metadata.attachments` 中的条目
+    pub fn metadata(&self) -> serde_json::Value {
+        serde_json::json!({
+            "id": self.file_id,
+            "name": self.name,
+            "size": self.size_bytes,
+            "mime_type": self.mime_type,
+            "width": self.width,
+            "height": self.height,
+        })
+    }
+}
+
+/// 一个客户端请求内已上传的图片，按图片地址的SHA-256索引
+///
+/// `n` 个回复和JSON修正重试各自向上游发送一次对话请求，共用同一份记录，每张图片只上传一次。
+/// clone共享同一份记录。
+#[derive(Debug, Clone, Default)]
+pub struct UploadedImages(Arc<Mutex<HashMap<String, ImageAttachment>>>);
+
+impl UploadedImages {
+    /// 返回这些图片的附件，尚未上传的图片先上传
+    ///
+    /// 上传期间持有锁，并行的回复会等待第一个回复上传完成后直接复用。
+    pub async fn upload_all<'a>(
+        &self,
+        images: impl Iterator<Item = &'a ImageUrl>,
+        client: &UpstreamClient,
+        access_token: &str,
+    ) -> ProxyResult<Vec<ImageAttachment>> {
+        let mut uploaded = self.0.lock().await;
+        let mut attachments = Vec::new();
+        for image in images {
+            let key = hex::encode(Sha256::digest(image.url.as_bytes()));
+            let attachment = match uploaded.get(&key) {
+                Some(attachment) => attachment.clone(),
+                None => {
+                    let attachment = upload_image(image, client, access_token).await?;
+                    uploaded.insert(key, attachment.clone());
+                    attachment
+                }
+            };
+            attachments.push(attachment);
+        }
+        Ok(attachments)
+    }
+}
+
+/// 文件服务创建上传时的响应
+#[derive(Debug, Deserialize)]
+struct CreatedUpload {
+    file_id: String,
+    upload_url: String,
+}
+
+/// 读取图片并上传到ChatGPT文件服务
+///
+/// 上传分三步：创建文件得到 `file_id` 和存储地址，把图片PUT到存储地址，再通知文件服务上传完成。
+async fn upload_image(
+    image: &ImageUrl,
+    client: &UpstreamClient,
+    access_token: &str,
+) -> ProxyResult<ImageAttachment> {
+    let (bytes, mime_type) = load_image(image).await?;
+    let (width, height) = image_dimensions(&bytes).unzip();
+    let name = format!("image.{}", mime_type.trim_start_matches("image/"));
+
+    let created: CreatedUpload = check_status(
+        client
+            .post(FILES_URL)
+            .bearer_auth(access_token)
+            .json(&serde_json::json!({
+                "file_name": name,
+                "file_size": bytes.len(),
+                "use_case": "multimodal",
+            }))
+            .send()
+            .await?,
+    )
+    .await?
+    .json()
+    .await?;
+
+    let size_bytes = bytes.len();
+    check_status(
+        client
+            .put(&created.upload_url)
+            .header("x-ms-blob-type", "BlockBlob")
+            .header(header::CONTENT_TYPE, &mime_type)
+            .body(bytes)
+            .send()
+            .await?,
+    )
+    .await?;
+
+    check_status(
+        client
+            .post(format!("{}/{}/uploaded", FILES_URL, created.file_id))
+            .bearer_auth(access_token)
+            .json(&serde_json::json!({}))
+            .send()
+            .await?,
+    )
+    .await?;
+
+    tracing::debug!("Uploaded image {} ({} bytes)", created.file_id, size_bytes);
+    Ok(ImageAttachment {
+        file_id: created.file_id,
+        name,
+        mime_type,
+        size_bytes,
+        width,
+        height,
+    })
+}
+
+async fn check_status(resp: reqwest::Response) -> ProxyResult<reqwest::Response> {
+    let status = resp.status();
+    if status.is_success() {
+        return Ok(resp);
+    }
+    let error_text = resp.text().await.unwrap_or_default();
+    tracing::error!("Image upload failed: status {}, body: {}", status, error_text);
+    Err(ProxyError::from_upstream_status(status, &error_text))
+}
+
+/// 读取 `data:` URL 或下载 http(s) 图片，返回内容和MIME类型
+async fn load_image(image: &ImageUrl) -> ProxyResult<(Vec<u8>, String)> {
+    let (bytes, mime_type) = if let Some(data_url) = image.url.strip_prefix("data:") {
+        let (mime_type, data) = data_url
+            .split_once(";base64,")
+            .ok_or_else(|| ProxyError::BadRequest("Image data URLs must be base64 encoded".to_string()))?;
+        let bytes = STANDARD
+            .decode(data)
+            .map_err(|e| ProxyError::BadRequest(format!("Invalid base64 image data: {}", e)))?;
+        (bytes, mime_type.to_string())
+    } else if image.url.starts_with("http://") || image.url.starts_with("https://") {
+        download_image(&image.url).await?
+    } else {
+        return Err(ProxyError::BadRequest("Image URLs must be data: or http(s) URLs".to_string()));
+    };
+
+    if !mime_type.starts_with("image/") {
+        return Err(ProxyError::BadRequest(format!("Unsupported image type '{}'", mime_type)));
+    }
+    if bytes.len() > MAX_IMAGE_BYTES {
+        return Err(too_large(bytes.len() as u64));
+    }
+    Ok((bytes, mime_type))
+}
+
+/// 下载客户端提供的图片地址
+///
+/// 地址由客户端决定，不能使用上游客户端（它不校验证书，并且对本机地址不走代理）。
+/// 每一跳都先解析域名并拒绝非公网地址，再只连接检查过的地址；响应体边读边检查大小。
+async fn download_image(image_url: &str) -> ProxyResult<(Vec<u8>, String)> {
+    let mut url = Url::parse(image_url).map_err(|e| ProxyError::BadRequest(format!("Invalid image URL: {}", e)))?;
+    for _ in 0..=MAX_REDIRECTS {
+        let mut resp = download_client(&url).await?.get(url.clone()).send().await?;
+        if resp.status().is_redirection() {
+            let location = resp
+                .headers()
+                .get(header::LOCATION)
+                .and_then(|v| v.to_str().ok())
+                .ok_or_else(|| ProxyError::BadRequest(format!("Image redirect without a location: {}", url)))?;
+            url = url
+                .join(location)
+                .map_err(|e| ProxyError::BadRequest(format!("Invalid image redirect: {}", e)))?;
+            continue;
+        }
+        if !resp.status().is_success() {
+            return Err(ProxyError::BadRequest(format!(
+                "Failed to download image {}: status {}",
+                url,
+                resp.status()
+            )));
+        }
+
+        let mime_type = resp
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.split(';').next())
+            .unwrap_or_default()
+            .trim()
+            .to_string();
+        if let Some(length) = resp.content_length().filter(|&length| length > MAX_IMAGE_BYTES as u64) {
+            return Err(too_large(length));
+        }
+        let mut bytes = Vec::new();
+        while let Some(chunk) = resp.chunk().await? {
+            if bytes.len() + chunk.len() > MAX_IMAGE_BYTES {
+                return Err(too_large((bytes.len() + chunk.len()) as u64));
+            }
+            bytes.extend_from_slice(&chunk);
+        }
+        return Ok((bytes, mime_type));
+    }
+    Err(ProxyError::BadRequest(format!("Too many redirects while downloading image {}", image_url)))
+}
+
+/// 解析图片地址的主机，全部地址都是公网地址时返回只连接这些地址的客户端，
+/// 避免DNS在检查之后被改指向内网地址
+async fn download_client(url: &Url) -> ProxyResult<reqwest::Client> {
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(ProxyError::BadRequest("Image URLs must be data: or http(s) URLs".to_string()));
+    }
+    let host = url
+        .host_str()
+        .ok_or_else(|| ProxyError::BadRequest("Image URL has no host".to_string()))?;
+    let port = url.port_or_known_default().unwrap_or(80);
+    let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host.trim_start_matches('[').trim_end_matches(']'), port))
+        .await
+        .map_err(|e| ProxyError::BadRequest(format!("Failed to resolve image host {}: {}", host, e)))?
+        .collect();
+    if addrs.is_empty() || addrs.iter().any(|addr| !is_public(addr.ip())) {
+        return Err(ProxyError::BadRequest(format!(
+            "Image host {} resolves to a private or local address",
+            host
+        )));
+    }
+
+    reqwest::Client::builder()
+        .no_proxy()
+        .redirect(redirect::Policy::none())
+        .connect_timeout(DOWNLOAD_CONNECT_TIMEOUT)
+        .timeout(DOWNLOAD_TIMEOUT)
+        .resolve_to_addrs(host, &addrs)
+        .build()
+        .map_err(ProxyError::from)
+}
+
+/// 是否为公网地址：排除本机、私有、链路本地（含云厂商元数据地址）、共享和保留地址
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || ip.is_multicast()
+                || a == 0
+                || a >= 240
+                || (a == 100 && (64..128).contains(&b)))
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public(IpAddr::V4(ip)),
+            None => {
+                let first = ip.segments()[0];
+                !(ip.is_loopback()
+                    || ip.is_unspecified()
+                    || ip.is_multicast()
+                    || (first & 0xfe00) == 0xfc00 // 唯一本地地址
+                    || (first & 0xffc0) == 0xfe80) // 链路本地地址
+            }
+        },
+    }
+}
+
+fn too_large(size: u64) -> ProxyError {
+    ProxyError::BadRequest(format!("Image is {} bytes, the limit is {} bytes", size, MAX_IMAGE_BYTES))
+}
+
+/// 从PNG、GIF、JPEG和WebP文件头读取宽高，其他格式返回None
+fn image_dimensions(bytes: &[u8]) -> Option<(u32, u32)> {
+    let be16 = |at: usize| Some(u16::from_be_bytes(bytes.get(at..at + 2)?.try_into().ok()?) as u32);
+    let le16 = |at: usize| Some(u16::from_le_bytes(bytes.get(at..at + 2)?.try_into().ok()?) as u32);
+    let be32 = |at: usize| Some(u32::from_be_bytes(bytes.get(at..at + 4)?.try_into().ok()?));
+    let le24 = |at: usize| {
+        let b = bytes.get(at..at + 3)?;
+        Some(b[0] as u32 | (b[1] as u32) << 8 | (b[2] as u32) << 16)
+    };
+
+    if bytes.starts_with(b"\x89PNG\r\n\x1a\n") {
+        return Some((be32(16)?, be32(20)?));
+    }
+    if bytes.starts_with(b"GIF8") {
+        return Some((le16(6)?, le16(8)?));
+    }
+    if bytes.starts_with(b"RIFF") && bytes.get(8..12) == Some(b"WEBP") {
+        return match bytes.get(12..16)? {
+            b"VP8X" => Some((le24(24)? + 1, le24(27)? + 1)),
+            b"VP8 " => Some((le16(26)? & 0x3fff, le16(28)? & 0x3fff)),
+            b"VP8L" => {
+                let bits = u32::from_le_bytes(bytes.get(21..25)?.try_into().ok()?);
+                Some(((bits & 0x3fff) + 1, ((bits >> 14) & 0x3fff) + 1))
+            }
+            _ => None,
+        };
+    }
+    if bytes.starts_with(b"\xff\xd8") {
+        // 逐个跳过JPEG段，直到SOF段
+        let mut at = 2;
+        while *bytes.get(at)? == 0xff {
+            let marker = *bytes.get(at + 1)?;
+            let length = be16(at + 2)? as usize;
+            if matches!(marker, 0xc0..=0xcf) && !matches!(marker, 0xc4 | 0xc8 | 0xcc) {
+                return Some((be16(at + 7)?, be16(at + 5)?));
+            }
+            at += 2 + length;
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_png_and_gif_dimensions() {
+        let mut png = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR".to_vec();
+        png.extend_from_slice(&640u32.to_be_bytes());
+        png.extend_from_slice(&480u32.to_be_bytes());
+        assert_eq!(image_dimensions(&png), Some((640, 480)));
+
+        let gif = b"GIF89a\x20\x03\x58\x02";
+        assert_eq!(image_dimensions(gif), Some((800, 600)));
+
+        assert_eq!(image_dimensions(b"not an image"), None);
+    }
+
+    #[test]
+    fn reads_jpeg_dimensions_after_app_segments() {
+        let jpeg = [
+            0xff, 0xd8, // SOI
+            0xff, 0xe0, 0x00, 0x04, 0x00, 0x00, // APP0
+            0xff, 0xc0, 0x00, 0x11, 0x08, 0x01, 0x2c, 0x01, 0x90, // SOF0: 高300 宽400
+        ];
+        assert_eq!(image_dimensions(&jpeg), Some((400, 300)));
+    }
+
+    #[test]
+    fn only_public_addresses_can_be_downloaded() {
+        for ip in ["8.8.8.8", "1.1.1.1", "2606:4700::1111"] {
+            assert!(is_public(ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+        ] {
+            assert!(!is_public(ip.parse().unwrap()), "{}", ip);
+        }
+    }
+
+    #[tokio::test]
+    async fn local_image_urls_are_rejected_before_connecting() {
+        for url in ["http://127.0.0.1:9100/a.png", "http://localhost/a.png", "http://[::1]/a.png"] {
+            let image = ImageUrl { url: url.to_string(), detail: None };
+            let err = load_image(&image).await.unwrap_err();
+            assert!(err.to_string().contains("private or local address"), "{}: {}", url, err);
+        }
+    }
+}
@@ -0,0 +1,381 @@
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use axum::body::Bytes;
+use futures::stream::{self, BoxStream, Stream, StreamExt};
+use std::collections::{HashMap, VecDeque};
+use std::pin::Pin;
+use std::sync::Arc;
+use crate::config::{AppConfig, OpenAiBackendConfig, CHATGPT_WEB_BACKEND};
+use crate::conversation_store::ConversationStore;
+use crate::error::{ProxyError, ProxyResult};
+use crate::http_client::{self, UpstreamClient};
+use crate::models::{ModelRegistry, ResolvedModel};
+use crate::openai_types::ChatCompletionRequest;
+use crate::proxy_service;
+use crate::credentials::CredentialsStore;
+use crate::token_cache::AccessTokenCache;
+
+/// 增量文本流，每一项是相对上一项新增的回复内容
+pub type DeltaStream = Pin<Box<dyn Stream<Item = ProxyResult<String>> + Send>>;
+
+/// 上游后端：接收OpenAI格式的请求，返回完整回复或增量文本流
+#[async_trait]
+pub trait UpstreamBackend: Send + Sync {
+    /// 后端名称，与配置中的名称一致
+    fn name(&self) -> &str;
+
+    /// 等待完整回复
+    async fn complete(&self, req: &ChatCompletionRequest) -> ProxyResult<String>;
+
+    /// 以增量文本流返回回复
+    async fn stream(&self, req: &ChatCompletionRequest) -> ProxyResult<DeltaStream>;
+}
+
+/// ChatGPT网页端后端
+pub struct ChatGptWebBackend {
+    client: UpstreamClient,
+    credentials: Arc<CredentialsStore>,
+    tokens: Arc<AccessTokenCache>,
+    conversations: Arc<ConversationStore>,
+}
+
+impl ChatGptWebBackend {
+    pub fn new(
+        client: UpstreamClient,
+        credentials: Arc<CredentialsStore>,
+        tokens: Arc<AccessTokenCache>,
+        conversations: Arc<ConversationStore>,
+    ) -> Self {
+        Self { client, credentials, tokens, conversations }
+    }
+}
+
+#[async_trait]
+impl UpstreamBackend for ChatGptWebBackend {
+    fn name(&self) -> &str {
+        CHATGPT_WEB_BACKEND
+    }
+
+    async fn complete(&self, req: &ChatCompletionRequest) -> ProxyResult<String> {
+        proxy_service::send_to_chatgpt(
+            req,
+            &self.client,
+            &self.credentials,
+            &self.tokens,
+            self.conversations.clone(),
+        )
+        .await
+    }
+
+    async fn stream(&self, req: &ChatCompletionRequest) -> ProxyResult<DeltaStream> {
+        proxy_service::stream_from_chatgpt(
+            req,
+            &self.client,
+            &self.credentials,
+            &self.tokens,
+            self.conversations.clone(),
+        )
+        .await
+    }
+}
+
+/// 转发到任意OpenAI兼容接口的后端（官方API、vLLM、本地mock等）
+pub struct OpenAiCompatBackend {
+    name: String,
+    base_url: String,
+    api_key: Option<String>,
+    client: UpstreamClient,
+}
+
+impl OpenAiCompatBackend {
+    pub fn new(config: &OpenAiBackendConfig, client: UpstreamClient) -> Self {
+        Self {
+            name: config.name.clone(),
+            base_url: config.base_url.clone(),
+            api_key: config.api_key.clone(),
+            client,
+        }
+    }
+
+    /// 发送请求，返回状态码成功的上游响应
+    async fn send(&self, req: &ChatCompletionRequest, stream: bool) -> ProxyResult<reqwest::Response> {
+        let payload = serde_json::json!({
+            "model": req.upstream_model(),
+            "messages": req.messages,
+            "max_tokens": req.max_tokens,
+            "temperature": req.temperature,
+            "top_p": req.top_p,
+            "frequency_penalty": req.frequency_penalty,
+            "presence_penalty": req.presence_penalty,
+            "stop": req.stop,
+            "user": req.user,
+            "stream": stream,
+        });
+
+        let url = format!("{}/chat/completions", self.base_url);
+        tracing::debug!("Forwarding request to backend {}: {}", self.name, url);
+
+        let mut request = self.client.post(&url).json(&payload);
+        if let Some(api_key) = &self.api_key {
+            request = request.bearer_auth(api_key);
+        }
+
+        let resp = request.send().await?;
+        let status = resp.status();
+        if !status.is_success() {
+            let error_text = resp.text().await.unwrap_or_default();
+            tracing::error!("Backend {} returned {}: {}", self.name, status, error_text);
+            return Err(ProxyError::from_upstream_status(status, &error_text));
+        }
+        Ok(resp)
+    }
+}
+
+#[async_trait]
+impl UpstreamBackend for OpenAiCompatBackend {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn complete(&self, req: &ChatCompletionRequest) -> ProxyResult<String> {
+        let json: serde_json::Value = self.send(req, false).await?.json().await?;
+        message_content(&json, &self.name)
+    }
+
+    async fn stream(&self, req: &ChatCompletionRequest) -> ProxyResult<DeltaStream> {
+        let resp = self.send(req, true).await?;
+        Ok(chunk_deltas(resp.bytes_stream().boxed()))
+    }
+}
+
+/// 非流式响应中的回复内容
+fn message_content(json: &serde_json::Value, backend: &str) -> ProxyResult<String> {
+    json.pointer("/choices/0/message/content")
+        .and_then(|c| c.as_str())
+        .map(str::to_string)
+        .ok_or_else(|| ProxyError::Parse(format!("Backend {} returned no message content", backend)))
+}
+
+/// 把 chat.completion.chunk 事件流的响应体转换为增量文本流
+fn chunk_deltas(body: BoxStream<'static, reqwest::Result<Bytes>>) -> DeltaStream {
+    let state = ChunkStreamState {
+        body,
+        buffer: Vec::new(),
+        pending: VecDeque::new(),
+        finished: false,
+    };
+
+    let deltas = stream::unfold(state, |mut state| async move {
+        loop {
+            if let Some(delta) = state.pending.pop_front() {
+                return Some((Ok(delta), state));
+            }
+            if state.finished {
+                return None;
+            }
+
+            match state.body.next().await {
+                Some(Ok(chunk)) => {
+                    state.buffer.extend_from_slice(&chunk);
+                    state.drain_lines();
+                }
+                Some(Err(e)) => {
+                    state.finished = true;
+                    tracing::error!("Failed to read backend stream: {}", e);
+                    return Some((Err(ProxyError::from(e)), state));
+                }
+                None => {
+                    state.buffer.push(b'\n');
+                    state.drain_lines();
+                    state.finished = true;
+                }
+            }
+        }
+    });
+
+    Box::pin(deltas)
+}
+
+/// 读取OpenAI格式 chat.completion.chunk 事件流的状态
+struct ChunkStreamState {
+    body: BoxStream<'static, reqwest::Result<Bytes>>,
+    buffer: Vec<u8>,
+    pending: VecDeque<String>,
+    finished: bool,
+}
+
+impl ChunkStreamState {
+    /// 解析缓冲区中的完整行，把其中的 `delta.content` 放入待输出队列
+    fn drain_lines(&mut self) {
+        while let Some(pos) = self.buffer.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = self.buffer.drain(..=pos).collect();
+            let line = String::from_utf8_lossy(&line);
+            let Some(data) = line.trim_end().strip_prefix("data:").map(str::trim_start) else {
+                continue;
+            };
+            if data == "[DONE]" {
+                self.finished = true;
+                return;
+            }
+
+            let content = serde_json::from_str::<serde_json::Value>(data)
+                .ok()
+                .and_then(|json| {
+                    json.pointer("/choices/0/delta/content")
+                        .and_then(|c| c.as_str())
+                        .map(str::to_string)
+                });
+            if let Some(content) = content.filter(|c| !c.is_empty()) {
+                self.pending.push_back(content);
+            }
+        }
+    }
+}
+
+/// 按模型注册表中的后端选择上游
+pub struct BackendRouter {
+    backends: HashMap<String, Arc<dyn UpstreamBackend>>,
+    default_backend: String,
+}
+
+impl BackendRouter {
+    /// 根据配置创建全部后端，并检查模型注册表引用的后端都存在
+    pub fn from_config(
+        config: Arc<AppConfig>,
+        models: &ModelRegistry,
+        client: UpstreamClient,
+        credentials: Arc<CredentialsStore>,
+        tokens: Arc<AccessTokenCache>,
+        conversations: Arc<ConversationStore>,
+    ) -> Result<Self> {
+        let mut backends: HashMap<String, Arc<dyn UpstreamBackend>> = HashMap::new();
+        backends.insert(
+            CHATGPT_WEB_BACKEND.to_string(),
+            Arc::new(ChatGptWebBackend::new(client.clone(), credentials, tokens, conversations)),
+        );
+        for backend_config in &config.openai_backends {
+            if backends.contains_key(&backend_config.name) {
+                bail!("Duplicate backend name: {}", backend_config.name);
+            }
+            // 单独设置了代理的后端使用自己的客户端
+            let client = match &backend_config.proxy {
+                Some(proxy) => http_client::build_backend_client(&config, proxy)
+                    .with_context(|| format!("Invalid proxy of backend '{}'", backend_config.name))?,
+                None => client.clone(),
+            };
+            backends.insert(
+                backend_config.name.clone(),
+                Arc::new(OpenAiCompatBackend::new(backend_config, client)),
+            );
+        }
+
+        let referenced = models.referenced_backends().chain(std::iter::once(config.default_backend.as_str()));
+        for name in referenced {
+            if !backends.contains_key(name) {
+                bail!("Unknown backend '{}' in configuration", name);
+            }
+        }
+
+        Ok(Self {
+            backends,
+            default_backend: config.default_backend.clone(),
+        })
+    }
+
+    /// 所有模型都由同一个后端处理，供测试使用
+    #[cfg(test)]
+    pub fn single(backend: Arc<dyn UpstreamBackend>) -> Self {
+        let default_backend = backend.name().to_string();
+        Self {
+            backends: HashMap::from([(default_backend.clone(), backend)]),
+            default_backend,
+        }
+    }
+
+    /// 选择处理该模型的后端，未单独配置后端的模型使用默认后端
+    pub fn select(&self, model: &ResolvedModel) -> Arc<dyn UpstreamBackend> {
+        let name = model.backend.as_deref().unwrap_or(&self.default_backend);
+        self.backends[name].clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHUNKS: &str = concat!(
+        ": keep-alive\n",
+        "data: {\"choices\":[{\"index\":0,\"delta\":{\"role\":\"assistant\"}}]}\n\n",
+        "data: {\"choices\":[{\"index\":0,\"delta\":{\"content\":\"\"}}]}\n\n",
+        "data: {\"choices\":[{\"index\":0,\"delta\":{\"content\":\"Hel\"}}]}\n\n",
+        "data:{\"choices\":[{\"index\":0,\"delta\":{\"content\":\"lo, 世界\"}}]}\r\n\r\n",
+        "data: {\"choices\":[{\"index\":0,\"delta\":{},\"finish_reason\":\"stop\"}]}\n\n",
+        "data: [DONE]\n\n",
+        "data: {\"choices\":[{\"index\":0,\"delta\":{\"content\":\"ignored\"}}]}\n\n",
+    );
+
+    fn state() -> ChunkStreamState {
+        ChunkStreamState {
+            body: stream::empty().boxed(),
+            buffer: Vec::new(),
+            pending: VecDeque::new(),
+            finished: false,
+        }
+    }
+
+    async fn collect(input: &[u8], chunk_size: usize) -> Vec<String> {
+        let chunks: Vec<reqwest::Result<Bytes>> =
+            input.chunks(chunk_size).map(|chunk| Ok(Bytes::copy_from_slice(chunk))).collect();
+        chunk_deltas(stream::iter(chunks).boxed())
+            .map(|delta| delta.unwrap())
+            .collect()
+            .await
+    }
+
+    #[test]
+    fn skips_role_only_and_empty_deltas() {
+        let mut state = state();
+        state.buffer.extend_from_slice(CHUNKS.as_bytes());
+        state.drain_lines();
+        assert_eq!(state.pending, ["Hel", "lo, 世界"]);
+        assert!(state.finished);
+    }
+
+    #[test]
+    fn keeps_incomplete_lines_buffered() {
+        let mut state = state();
+        state.buffer.extend_from_slice(b"data: {\"choices\":[{\"delta\":{\"content\":\"Hi\"}}]}\ndata: {\"choi");
+        state.drain_lines();
+        assert_eq!(state.pending, ["Hi"]);
+        assert_eq!(state.buffer, b"data: {\"choi");
+        assert!(!state.finished);
+    }
+
+    #[tokio::test]
+    async fn lines_split_across_chunks_are_joined() {
+        // 多字节字符也可能被拆到两个数据块中
+        for chunk_size in [1, 2, 7, 64, CHUNKS.len()] {
+            assert_eq!(collect(CHUNKS.as_bytes(), chunk_size).await, ["Hel", "lo, 世界"], "chunk size {}", chunk_size);
+        }
+    }
+
+    #[tokio::test]
+    async fn last_line_without_newline_is_parsed() {
+        let input = b"data: {\"choices\":[{\"delta\":{\"content\":\"tail\"}}]}";
+        assert_eq!(collect(input, 5).await, ["tail"]);
+    }
+
+    #[test]
+    fn complete_requires_message_content() {
+        let json = serde_json::json!({ "choices": [{ "message": { "role": "assistant", "content": "Hi" } }] });
+        assert_eq!(message_content(&json, "local").unwrap(), "Hi");
+
+        for json in [
+            serde_json::json!({ "choices": [] }),
+            serde_json::json!({ "choices": [{ "message": { "role": "assistant", "content": null } }] }),
+        ] {
+            let err = message_content(&json, "local").unwrap_err();
+            assert!(matches!(&err, ProxyError::Parse(message) if message == "Backend local returned no message content"));
+        }
+    }
+}
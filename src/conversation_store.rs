@@ -0,0 +1,303 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+use crate::openai_types::Message;
+
+/// 客户端会话在上游对应的状态
+#[derive(Debug, Clone)]
+struct ConversationState {
+    conversation_id: String,
+    last_message_id: String,
+    // 已发送给上游的消息数（包含上游的回复）
+    message_count: usize,
+    // 上述消息的指纹，用于确认客户端带来的历史与上游一致
+    fingerprint: u64,
+    updated_at: Instant,
+}
+
+/// 续接已有上游会话时需要的信息
+#[derive(Debug, Clone)]
+pub struct Continuation {
+    pub conversation_id: String,
+    pub parent_message_id: String,
+    // 客户端消息中尚未发给上游的部分从这里开始
+    pub new_messages_from: usize,
+}
+
+/// 一轮对话结束后写回存储所需的信息
+#[derive(Debug, Clone)]
+pub struct PendingTurn {
+    key: String,
+    message_count: usize,
+    hasher: DefaultHasher,
+}
+
+/// 上游已经回复、尚未写回存储的一轮对话
+struct AnsweredTurn {
+    store: Arc<ConversationStore>,
+    turn: PendingTurn,
+    conversation_id: String,
+    last_message_id: String,
+}
+
+/// 一次请求中等待保存的对话，clone出的请求共用
+///
+/// 上游的回复经过 `stop`、`max_tokens`、工具调用和JSON输出处理后才返回给客户端，
+/// 客户端下一轮带来的是处理后的内容，所以由后端记下上游会话信息，
+/// 再由处理函数在确定返回给客户端的内容后调用 `record` 写回存储。
+#[derive(Clone, Default)]
+pub struct TurnRecorder(Arc<Mutex<Option<AnsweredTurn>>>);
+
+impl fmt::Debug for TurnRecorder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("TurnRecorder")
+    }
+}
+
+impl TurnRecorder {
+    /// 上游完成了本轮回复，记下上游会话id和回复消息id
+    pub async fn answered(
+        &self,
+        store: Arc<ConversationStore>,
+        turn: PendingTurn,
+        conversation_id: String,
+        last_message_id: String,
+    ) {
+        *self.0.lock().await = Some(AnsweredTurn { store, turn, conversation_id, last_message_id });
+    }
+
+    /// 放弃保存本轮对话，下一轮开始新的上游会话
+    pub async fn forget(&self) {
+        self.0.lock().await.take();
+    }
+
+    /// 按返回给客户端的回复保存本轮对话，没有可保存的上游会话时什么也不做
+    pub async fn record(&self, reply: &str) {
+        let Some(answered) = self.0.lock().await.take() else {
+            return;
+        };
+        answered
+            .store
+            .complete_turn(answered.turn, answered.conversation_id, answered.last_message_id, reply)
+            .await;
+    }
+}
+
+/// 客户端会话id -> 上游 conversation_id / 最后一条消息id 的映射，带TTL和容量上限
+pub struct ConversationStore {
+    entries: Mutex<HashMap<String, ConversationState>>,
+    ttl: Duration,
+    max_entries: usize,
+}
+
+impl ConversationStore {
+    pub fn new(ttl: Duration, max_entries: usize) -> Self {
+        Self {
+            entries: Mutex::new(HashMap::new()),
+            ttl,
+            max_entries,
+        }
+    }
+
+    /// 查找可续接的上游会话，客户端历史与上次不一致或已过期时返回None
+    pub async fn lookup(&self, key: &str, messages: &[Message]) -> Option<Continuation> {
+        let mut entries = self.entries.lock().await;
+        let state = entries.get(key)?;
+
+        if state.updated_at.elapsed() >= self.ttl {
+            entries.remove(key);
+            return None;
+        }
+
+        // 必须有新消息，且之前的消息与上游看到的完全一致
+        if messages.len() <= state.message_count
+            || fingerprint(&messages[..state.message_count]).finish() != state.fingerprint
+        {
+            tracing::debug!("会话 {} 的历史与上游不一致，开始新的上游会话", key);
+            return None;
+        }
+
+        Some(Continuation {
+            conversation_id: state.conversation_id.clone(),
+            parent_message_id: state.last_message_id.clone(),
+            new_messages_from: state.message_count,
+        })
+    }
+
+    /// 开始一轮对话，记录本次请求的全部消息
+    pub fn begin_turn(key: &str, messages: &[Message]) -> PendingTurn {
+        PendingTurn {
+            key: key.to_string(),
+            message_count: messages.len(),
+            hasher: fingerprint(messages),
+        }
+    }
+
+    /// 一轮对话结束，保存上游会话id、回复消息id以及包含回复在内的历史指纹
+    pub async fn complete_turn(
+        &self,
+        turn: PendingTurn,
+        conversation_id: String,
+        last_message_id: String,
+        reply: &str,
+    ) {
+        let mut hasher = turn.hasher;
+        hash_message(&mut hasher, "assistant", reply);
+
+        let mut entries = self.entries.lock().await;
+        if !entries.contains_key(&turn.key) && entries.len() >= self.max_entries {
+            // 容量已满，淘汰最久未使用的会话
+            let oldest = entries
+                .iter()
+                .min_by_key(|(_, state)| state.updated_at)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                entries.remove(&oldest);
+            }
+        }
+
+        entries.insert(
+            turn.key,
+            ConversationState {
+                conversation_id,
+                last_message_id,
+                message_count: turn.message_count + 1,
+                fingerprint: hasher.finish(),
+                updated_at: Instant::now(),
+            },
+        );
+    }
+
+    /// 启动定期清理过期会话的后台任务
+    pub fn start_eviction_task(self: Arc<Self>) {
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(Duration::from_secs(60)).await;
+
+                let mut entries = self.entries.lock().await;
+                let before = entries.len();
+                entries.retain(|_, state| state.updated_at.elapsed() < self.ttl);
+                let evicted = before - entries.len();
+                if evicted > 0 {
+                    tracing::debug!("Evicted {} expired conversations", evicted);
+                }
+            }
+        });
+    }
+}
+
+/// 计算消息列表的指纹
+fn fingerprint(messages: &[Message]) -> DefaultHasher {
+    let mut hasher = DefaultHasher::new();
+    for message in messages {
+        hash_message(&mut hasher, &message.role, &message.content.text());
+        for image in message.content.images() {
+            image.url.hash(&mut hasher);
+        }
+    }
+    hasher
+}
+
+fn hash_message(hasher: &mut DefaultHasher, role: &str, content: &str) {
+    role.hash(hasher);
+    content.hash(hasher);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn messages(contents: &[(&str, &str)]) -> Vec<Message> {
+        contents.iter().map(|(role, content)| Message::text(role, content.to_string())).collect()
+    }
+
+    async fn complete(store: &ConversationStore, key: &str, history: &[Message], conversation_id: &str, reply: &str) {
+        let turn = ConversationStore::begin_turn(key, history);
+        store
+            .complete_turn(turn, conversation_id.to_string(), format!("{}-reply", conversation_id), reply)
+            .await;
+    }
+
+    #[tokio::test]
+    async fn continues_when_the_history_matches() {
+        let store = ConversationStore::new(Duration::from_secs(60), 10);
+        let first = messages(&[("user", "hi")]);
+        assert!(store.lookup("chat", &first).await.is_none());
+        complete(&store, "chat", &first, "conv-1", "hello").await;
+
+        let next = messages(&[("user", "hi"), ("assistant", "hello"), ("user", "how are you?")]);
+        let continuation = store.lookup("chat", &next).await.unwrap();
+        assert_eq!(continuation.conversation_id, "conv-1");
+        assert_eq!(continuation.parent_message_id, "conv-1-reply");
+        assert_eq!(continuation.new_messages_from, 2);
+
+        assert!(store.lookup("other", &next).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn edited_history_starts_a_new_conversation() {
+        let store = ConversationStore::new(Duration::from_secs(60), 10);
+        complete(&store, "chat", &messages(&[("user", "hi")]), "conv-1", "hello").await;
+
+        // 客户端改写了上游的回复
+        let edited = messages(&[("user", "hi"), ("assistant", "something else"), ("user", "and?")]);
+        assert!(store.lookup("chat", &edited).await.is_none());
+        // 没有新消息时也无法续接
+        let repeated = messages(&[("user", "hi"), ("assistant", "hello")]);
+        assert!(store.lookup("chat", &repeated).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn expired_conversations_are_dropped() {
+        let store = ConversationStore::new(Duration::ZERO, 10);
+        let first = messages(&[("user", "hi")]);
+        complete(&store, "chat", &first, "conv-1", "hello").await;
+
+        let next = messages(&[("user", "hi"), ("assistant", "hello"), ("user", "again")]);
+        assert!(store.lookup("chat", &next).await.is_none());
+        assert!(store.entries.lock().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn least_recently_used_conversation_is_evicted_at_capacity() {
+        let store = ConversationStore::new(Duration::from_secs(60), 2);
+        let first = messages(&[("user", "hi")]);
+        for (key, conversation_id) in [("a", "conv-a"), ("b", "conv-b"), ("c", "conv-c")] {
+            complete(&store, key, &first, conversation_id, "hello").await;
+            tokio::time::sleep(Duration::from_millis(2)).await;
+        }
+
+        let entries = store.entries.lock().await;
+        assert_eq!(entries.len(), 2);
+        assert!(!entries.contains_key("a"));
+        assert!(entries.contains_key("b") && entries.contains_key("c"));
+    }
+
+    #[tokio::test]
+    async fn recorder_saves_the_reply_returned_to_the_client() {
+        let store = Arc::new(ConversationStore::new(Duration::from_secs(60), 10));
+        let first = messages(&[("user", "hi")]);
+        let recorder = TurnRecorder::default();
+        let turn = ConversationStore::begin_turn("chat", &first);
+        recorder.clone().answered(store.clone(), turn, "conv-1".to_string(), "reply-1".to_string()).await;
+        // 上游的完整回复是 "hello there"，客户端收到的是截断后的 "hello"
+        recorder.record("hello").await;
+
+        let next = messages(&[("user", "hi"), ("assistant", "hello"), ("user", "again")]);
+        assert_eq!(store.lookup("chat", &next).await.unwrap().conversation_id, "conv-1");
+        let full = messages(&[("user", "hi"), ("assistant", "hello there"), ("user", "again")]);
+        assert!(store.lookup("chat", &full).await.is_none());
+
+        // 放弃后不再保存
+        let turn = ConversationStore::begin_turn("other", &first);
+        recorder.answered(store.clone(), turn, "conv-2".to_string(), "reply-2".to_string()).await;
+        recorder.forget().await;
+        recorder.record("hello").await;
+        assert!(store.lookup("other", &next).await.is_none());
+    }
+}
@@ -0,0 +1,262 @@
+use anyhow::{Context, Result};
+use reqwest::header::{HeaderMap, SET_COOKIE};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::RwLock;
+
+use crate::config::AppConfig;
+
+/// 网页端会话cookie的名称，过长时上游会拆分为 `.0`、`.1` 等多个cookie
+pub const SESSION_COOKIE: &str = "__Secure-next-auth.session-token";
+
+/// 当前使用的ChatGPT认证信息
+#[derive(Debug, Clone)]
+pub struct Credentials {
+    pub session_token: String,
+    pub authorization: String,
+    /// Cloudflare验证cookie，未配置时为空
+    pub cf_clearance: String,
+}
+
+/// 持久化到磁盘的会话令牌
+#[derive(Debug, Serialize, Deserialize)]
+struct PersistedCredentials {
+    session_token: String,
+    // 写入时配置中的会话令牌，配置被手动修改后以配置为准
+    configured_session_token: String,
+    updated_at: u64,
+}
+
+/// 可在运行时替换的认证信息，由请求处理和后台刷新任务共享
+pub struct CredentialsStore {
+    current: RwLock<Credentials>,
+    configured_session_token: RwLock<String>,
+    persist_path: PathBuf,
+}
+
+impl CredentialsStore {
+    /// 从配置加载认证信息，磁盘上有之前轮换得到的会话令牌时优先使用
+    pub fn load(config: &AppConfig) -> Self {
+        let persist_path = PathBuf::from(&config.credentials_file);
+        let mut session_token = config.chatgpt_session_token.clone();
+
+        match read_persisted(&persist_path) {
+            Ok(Some(persisted)) if persisted.configured_session_token == config.chatgpt_session_token => {
+                tracing::info!("Using rotated session token from {}", persist_path.display());
+                session_token = persisted.session_token;
+            }
+            Ok(Some(_)) => {
+                tracing::info!(
+                    "CHATGPT_SESSION_TOKEN changed since {} was written, using the configured value",
+                    persist_path.display()
+                );
+            }
+            Ok(None) => {}
+            Err(e) => tracing::warn!("Failed to read {}: {:#}", persist_path.display(), e),
+        }
+
+        Self {
+            current: RwLock::new(Credentials {
+                session_token,
+                authorization: config.chatgpt_authorization.clone(),
+                cf_clearance: config.cf_clearance.clone().unwrap_or_default(),
+            }),
+            configured_session_token: RwLock::new(config.chatgpt_session_token.clone()),
+            persist_path,
+        }
+    }
+
+    /// 当前认证信息的快照
+    pub async fn snapshot(&self) -> Credentials {
+        self.current.read().await.clone()
+    }
+
+    pub async fn session_token(&self) -> String {
+        self.current.read().await.session_token.clone()
+    }
+
+    /// 配置重载后更新认证信息，返回会话令牌或访问令牌是否有变化
+    ///
+    /// 配置中的会话令牌没有变化时，继续使用上游轮换后的值。
+    pub async fn reload(&self, config: &AppConfig) -> bool {
+        let mut configured = self.configured_session_token.write().await;
+        let mut current = self.current.write().await;
+
+        let session_changed = *configured != config.chatgpt_session_token;
+        if session_changed {
+            *configured = config.chatgpt_session_token.clone();
+            current.session_token = config.chatgpt_session_token.clone();
+        }
+        let authorization_changed = current.authorization != config.chatgpt_authorization;
+        current.authorization = config.chatgpt_authorization.clone();
+        current.cf_clearance = config.cf_clearance.clone().unwrap_or_default();
+        session_changed || authorization_changed
+    }
+
+    /// 保存上游轮换后的会话令牌，并写入磁盘以便重启后继续使用
+    pub async fn update_session_token(&self, session_token: String) {
+        {
+            let mut current = self.current.write().await;
+            if current.session_token == session_token {
+                return;
+            }
+            current.session_token = session_token.clone();
+        }
+        tracing::info!("Session token rotated by upstream");
+
+        let persisted = PersistedCredentials {
+            session_token,
+            configured_session_token: self.configured_session_token.read().await.clone(),
+            updated_at: SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs(),
+        };
+        if let Err(e) = write_persisted(&self.persist_path, &persisted).await {
+            tracing::error!("Failed to persist rotated session token: {:#}", e);
+        }
+    }
+}
+
+/// 从上游响应的 Set-Cookie 中提取轮换后的会话令牌，支持被拆分的cookie
+pub fn rotated_session_token(headers: &HeaderMap) -> Option<String> {
+    let mut whole = None;
+    let mut chunks = BTreeMap::new();
+
+    for value in headers.get_all(SET_COOKIE) {
+        let Some((name, value)) = value
+            .to_str()
+            .ok()
+            .and_then(|cookie| cookie.split(';').next())
+            .and_then(|pair| pair.trim().split_once('='))
+        else {
+            continue;
+        };
+        // 空值表示上游删除了该cookie
+        if value.is_empty() {
+            continue;
+        }
+
+        if name == SESSION_COOKIE {
+            whole = Some(value.to_string());
+        } else if let Some(index) = name
+            .strip_prefix(SESSION_COOKIE)
+            .and_then(|suffix| suffix.strip_prefix('.'))
+            .and_then(|index| index.parse::<u32>().ok())
+        {
+            chunks.insert(index, value.to_string());
+        }
+    }
+
+    whole.or_else(|| (!chunks.is_empty()).then(|| chunks.into_values().collect()))
+}
+
+fn read_persisted(path: &Path) -> Result<Option<PersistedCredentials>> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    let content = std::fs::read_to_string(path)?;
+    Ok(Some(serde_json::from_str(&content)?))
+}
+
+/// 先写临时文件再重命名，避免写到一半时进程退出留下损坏的文件
+///
+/// 文件中保存会话令牌，临时文件在写入内容之前就设为只有当前用户可读写。
+async fn write_persisted(path: &Path, persisted: &PersistedCredentials) -> Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    let content = serde_json::to_vec_pretty(persisted)?;
+
+    let mut options = tokio::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let mut file = options
+        .open(&tmp_path)
+        .await
+        .with_context(|| format!("creating {}", tmp_path.display()))?;
+    // 临时文件可能是之前留下的，mode只对新建的文件生效
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(std::fs::Permissions::from_mode(0o600))
+            .await
+            .with_context(|| format!("restricting permissions of {}", tmp_path.display()))?;
+    }
+    file.write_all(&content)
+        .await
+        .with_context(|| format!("writing {}", tmp_path.display()))?;
+    file.sync_all().await?;
+
+    tokio::fs::rename(&tmp_path, path)
+        .await
+        .with_context(|| format!("renaming to {}", path.display()))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderValue;
+
+    fn set_cookies(cookies: &[&str]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for cookie in cookies {
+            headers.append(SET_COOKIE, HeaderValue::from_str(cookie).unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn whole_session_cookie_is_extracted() {
+        let headers = set_cookies(&[
+            "__cf_bm=abc; Path=/; HttpOnly",
+            "__Secure-next-auth.session-token=new-token; Path=/; Secure; HttpOnly; SameSite=Lax",
+        ]);
+        assert_eq!(rotated_session_token(&headers).as_deref(), Some("new-token"));
+    }
+
+    #[test]
+    fn chunked_session_cookies_are_joined_in_order() {
+        let headers = set_cookies(&[
+            "__Secure-next-auth.session-token.1=second; Path=/",
+            "__Secure-next-auth.session-token.0=first; Path=/",
+        ]);
+        assert_eq!(rotated_session_token(&headers).as_deref(), Some("firstsecond"));
+    }
+
+    #[test]
+    fn deleted_and_unrelated_cookies_are_ignored() {
+        let headers = set_cookies(&[
+            "__Secure-next-auth.session-token=; Path=/; Max-Age=0",
+            "__Secure-next-auth.callback-url=https%3A%2F%2Fchat.openai.com; Path=/",
+            "__Secure-next-auth.session-token.x=junk; Path=/",
+        ]);
+        assert_eq!(rotated_session_token(&headers), None);
+        assert_eq!(rotated_session_token(&HeaderMap::new()), None);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn persisted_credentials_are_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join(format!("chatgpt-proxy-credentials-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("credentials.json");
+        // 之前留下的临时文件权限过宽
+        std::fs::write(path.with_extension("tmp"), "stale").unwrap();
+        std::fs::set_permissions(path.with_extension("tmp"), std::fs::Permissions::from_mode(0o644)).unwrap();
+
+        let persisted = PersistedCredentials {
+            session_token: "rotated".to_string(),
+            configured_session_token: "configured".to_string(),
+            updated_at: 0,
+        };
+        write_persisted(&path, &persisted).await.unwrap();
+
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(read_persisted(&path).unwrap().unwrap().session_token, "rotated");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,268 @@
+use axum::{
+    http::{header::RETRY_AFTER, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use reqwest::StatusCode as UpstreamStatus;
+use std::time::Duration;
+use thiserror::Error;
+
+/// 代理处理请求时可能出现的错误，每种错误对应一个HTTP状态码和OpenAI格式的错误体
+#[derive(Debug, Error)]
+pub enum ProxyError {
+    /// 上游认证信息（session token / access token）已失效
+    #[error("Upstream authentication expired: {0}")]
+    AuthExpired(String),
+
+    /// 上游返回403，通常是Cloudflare拦截
+    #[error("Upstream rejected the request (403): {0}")]
+    UpstreamForbidden(String),
+
+    /// 上游返回429
+    #[error("Upstream rate limit reached: {0}")]
+    UpstreamRateLimited(String),
+
+    /// 上游返回5xx
+    #[error("Upstream server error ({status}): {message}")]
+    UpstreamServer { status: u16, message: String },
+
+    /// 无法连接上游或读取上游响应
+    #[error("Upstream unavailable: {0}")]
+    UpstreamUnavailable(String),
+
+    /// 上游响应无法解析
+    #[error("Failed to parse upstream response: {0}")]
+    Parse(String),
+
+    /// 上游的回复多次修正后仍不符合要求的输出格式
+    #[error("{0}")]
+    InvalidOutput(String),
+
+    /// 上游请求超时
+    #[error("Upstream request timed out: {0}")]
+    Timeout(String),
+
+    /// 客户端没有提供有效的API key
+    #[error("{0}")]
+    Unauthorized(String),
+
+    /// 客户端超过每分钟的请求数或token数限制，`retry_after` 后可以重试
+    #[error("{message}")]
+    RateLimited { message: String, retry_after: Duration },
+
+    /// 客户端API key的每日/每月配额已用完
+    #[error("{0}")]
+    QuotaExceeded(String),
+
+    /// 请求的模型不在模型注册表中
+    #[error("{0}")]
+    ModelNotFound(String),
+
+    /// 请求使用了代理未启用或不支持的功能
+    #[error("{0}")]
+    Unsupported(String),
+
+    /// 客户端请求无效
+    #[error("{0}")]
+    BadRequest(String),
+
+    /// 代理内部错误
+    #[error("Internal error: {0}")]
+    Internal(String),
+}
+
+pub type ProxyResult<T> = Result<T, ProxyError>;
+
+impl ProxyError {
+    /// 根据上游的非成功状态码构造错误
+    pub fn from_upstream_status(status: UpstreamStatus, body: &str) -> Self {
+        let message = truncate(body, 500);
+        match status.as_u16() {
+            401 => Self::AuthExpired(message),
+            403 => Self::UpstreamForbidden(message),
+            429 => Self::UpstreamRateLimited(message),
+            400 | 404 | 413 | 422 => Self::BadRequest(format!("Upstream rejected the request: {}", message)),
+            code => Self::UpstreamServer { status: code, message },
+        }
+    }
+
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            Self::AuthExpired(_)
+            | Self::UpstreamForbidden(_)
+            | Self::UpstreamServer { .. }
+            | Self::UpstreamUnavailable(_)
+            | Self::Parse(_)
+            | Self::InvalidOutput(_) => StatusCode::BAD_GATEWAY,
+            Self::UpstreamRateLimited(_) | Self::RateLimited { .. } | Self::QuotaExceeded(_) => {
+                StatusCode::TOO_MANY_REQUESTS
+            }
+            Self::Timeout(_) => StatusCode::GATEWAY_TIMEOUT,
+            Self::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            Self::ModelNotFound(_) => StatusCode::NOT_FOUND,
+            Self::Unsupported(_) | Self::BadRequest(_) => StatusCode::BAD_REQUEST,
+            Self::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// OpenAI错误体中的 `type` 字段
+    pub fn error_type(&self) -> &'static str {
+        match self {
+            Self::AuthExpired(_)
+            | Self::UpstreamForbidden(_)
+            | Self::UpstreamServer { .. }
+            | Self::UpstreamUnavailable(_)
+            | Self::Parse(_)
+            | Self::InvalidOutput(_) => "upstream_error",
+            Self::UpstreamRateLimited(_) | Self::RateLimited { .. } => "rate_limit_error",
+            Self::QuotaExceeded(_) => "insufficient_quota",
+            Self::Timeout(_) => "timeout_error",
+            Self::Unauthorized(_) | Self::ModelNotFound(_) | Self::Unsupported(_) | Self::BadRequest(_) => {
+                "invalid_request_error"
+            }
+            Self::Internal(_) => "server_error",
+        }
+    }
+
+    /// OpenAI错误体中的 `code` 字段
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::AuthExpired(_) => "upstream_auth_expired",
+            Self::UpstreamForbidden(_) => "upstream_forbidden",
+            Self::UpstreamRateLimited(_) => "upstream_rate_limited",
+            Self::RateLimited { .. } => "rate_limit_exceeded",
+            Self::QuotaExceeded(_) => "insufficient_quota",
+            Self::UpstreamServer { .. } => "upstream_server_error",
+            Self::UpstreamUnavailable(_) => "upstream_unavailable",
+            Self::Parse(_) => "upstream_parse_error",
+            Self::InvalidOutput(_) => "invalid_response_format",
+            Self::Timeout(_) => "upstream_timeout",
+            Self::Unauthorized(_) => "invalid_api_key",
+            Self::ModelNotFound(_) => "model_not_found",
+            Self::Unsupported(_) => "unsupported_feature",
+            Self::BadRequest(_) => "invalid_request",
+            Self::Internal(_) => "internal_error",
+        }
+    }
+
+    /// OpenAI格式的错误体 `{"error": {"message", "type", "code"}}`
+    pub fn to_body(&self) -> serde_json::Value {
+        serde_json::json!({
+            "error": {
+                "message": self.to_string(),
+                "type": self.error_type(),
+                "param": null,
+                "code": self.code(),
+            }
+        })
+    }
+}
+
+impl IntoResponse for ProxyError {
+    fn into_response(self) -> Response {
+        let mut response = (self.status_code(), Json(self.to_body())).into_response();
+        if let Self::RateLimited { retry_after, .. } = &self {
+            // Retry-After 只支持整数秒，向上取整
+            let secs = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+            response.headers_mut().insert(RETRY_AFTER, HeaderValue::from(secs));
+        }
+        response
+    }
+}
+
+impl From<reqwest::Error> for ProxyError {
+    fn from(e: reqwest::Error) -> Self {
+        if e.is_timeout() {
+            Self::Timeout(e.to_string())
+        } else if e.is_decode() {
+            Self::Parse(e.to_string())
+        } else {
+            Self::UpstreamUnavailable(e.to_string())
+        }
+    }
+}
+
+impl From<anyhow::Error> for ProxyError {
+    fn from(e: anyhow::Error) -> Self {
+        Self::Internal(format!("{:#}", e))
+    }
+}
+
+/// 截断过长的上游错误内容，避免把整页HTML返回给客户端
+fn truncate(text: &str, max_chars: usize) -> String {
+    if text.chars().count() > max_chars {
+        format!("{}...", text.chars().take(max_chars).collect::<String>())
+    } else {
+        text.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::HttpBody;
+
+    async fn render(error: ProxyError) -> (StatusCode, Option<String>, serde_json::Value) {
+        let response = error.into_response();
+        let status = response.status();
+        let retry_after = response.headers().get(RETRY_AFTER).map(|v| v.to_str().unwrap().to_string());
+        let mut body = response.into_body();
+        let mut bytes = Vec::new();
+        while let Some(chunk) = body.data().await {
+            bytes.extend_from_slice(&chunk.unwrap());
+        }
+        (status, retry_after, serde_json::from_slice(&bytes).unwrap())
+    }
+
+    fn upstream(status: u16, body: &str) -> ProxyError {
+        ProxyError::from_upstream_status(UpstreamStatus::from_u16(status).unwrap(), body)
+    }
+
+    #[tokio::test]
+    async fn errors_map_to_status_and_openai_error_body() {
+        let cases = [
+            (upstream(401, "expired"), 502, "upstream_error", "upstream_auth_expired"),
+            (upstream(403, "<html>"), 502, "upstream_error", "upstream_forbidden"),
+            (upstream(429, "slow down"), 429, "rate_limit_error", "upstream_rate_limited"),
+            (upstream(500, "oops"), 502, "upstream_error", "upstream_server_error"),
+            (upstream(503, "down"), 502, "upstream_error", "upstream_server_error"),
+            (upstream(422, "bad"), 400, "invalid_request_error", "invalid_request"),
+            (ProxyError::Timeout("no response".to_string()), 504, "timeout_error", "upstream_timeout"),
+            (ProxyError::QuotaExceeded("daily".to_string()), 429, "insufficient_quota", "insufficient_quota"),
+            (ProxyError::Unauthorized("no key".to_string()), 401, "invalid_request_error", "invalid_api_key"),
+        ];
+        for (error, status, error_type, code) in cases {
+            let message = error.to_string();
+            let (actual_status, retry_after, body) = render(error).await;
+            assert_eq!(actual_status.as_u16(), status, "{}", message);
+            assert_eq!(retry_after, None);
+            assert_eq!(
+                body,
+                serde_json::json!({ "error": { "message": message, "type": error_type, "param": null, "code": code } })
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn upstream_error_bodies_are_truncated() {
+        let (_, _, body) = render(upstream(502, &"x".repeat(2000))).await;
+        let message = body["error"]["message"].as_str().unwrap();
+        assert_eq!(message, format!("Upstream server error (502): {}...", "x".repeat(500)));
+    }
+
+    #[tokio::test]
+    async fn retry_after_rounds_up_to_whole_seconds() {
+        for (retry_after, header) in [
+            (Duration::from_millis(200), "1"),
+            (Duration::from_secs(2), "2"),
+            (Duration::from_millis(2001), "3"),
+        ] {
+            let error = ProxyError::RateLimited { message: "Rate limit reached".to_string(), retry_after };
+            let (status, actual, body) = render(error).await;
+            assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+            assert_eq!(actual.as_deref(), Some(header), "{:?}", retry_after);
+            assert_eq!(body["error"]["code"], "rate_limit_exceeded");
+            assert_eq!(body["error"]["type"], "rate_limit_error");
+        }
+    }
+}
@@ -1,14 +1,28 @@
-use axum::{Json, extract::{Extension, ConnectInfo}};
+use axum::{Json, extract::{Extension, Path, rejection::JsonRejection}, http::{HeaderMap, header::CONTENT_TYPE}};
+use axum::response::{IntoResponse, Response, sse::{Event, KeepAlive, Sse}};
+use futures::future::join_all;
+use futures::stream;
+use futures::{SinkExt, StreamExt};
 use uuid::Uuid;
+use std::convert::Infallible;
 use std::sync::Arc;
-use std::net::SocketAddr;
+use std::net::IpAddr;
+use crate::backend::{BackendRouter, UpstreamBackend};
 use crate::config::AppConfig;
-use crate::middleware::SharedRequestTracker;
-use crate::openai_types::{ChatCompletionRequest, ChatCompletionResponse, Choice, MessageResponse, Usage};
-use crate::proxy_service;
+use crate::error::{ProxyError, ProxyResult};
+use crate::metrics::SharedMetrics;
+use crate::models::{ModelObject, ModelRegistry};
+use crate::middleware::{ClientIdentity, ClientIp, KeyUsage, SharedRequestTracker, TokenReservation};
+use crate::openai_types::{
+    ChatCompletionChunk, ChatCompletionRequest, ChatCompletionResponse, Choice, ChunkChoice, CompletionRequest,
+    Delta, Message, MessageResponse, StopSequences, TextChoice, TextCompletionResponse, ToolCall, ToolCallDelta, Usage,
+};
+use crate::output_limits::{FinishReason, OutputLimiter};
+use crate::response_format::{self, JsonFormat};
+use crate::tool_calls::{self, Detected, ToolCallDetector, ToolSet};
 use crate::utils;
 use crate::middleware;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 use serde::Serialize;
 
 /// 系统状态信息
@@ -19,6 +33,7 @@ pub struct SystemStatus {
     server_port: u16,
     rate_limits: RateLimits,
     stats: SystemStats,
+    api_keys: Vec<KeyUsage>,
 }
 
 #[derive(Serialize)]
@@ -31,158 +46,722 @@ pub struct RateLimits {
 pub struct SystemStats {
     total_requests: u64,
     total_tokens: u64,
-    active_ips: usize,
+    active_clients: usize,
 }
 
-// 系统启动时间
-static mut START_TIME: Option<SystemTime> = None;
-// 系统统计数据
-static mut TOTAL_REQUESTS: u64 = 0;
-static mut TOTAL_TOKENS: u64 = 0;
+/// `/v1/models` 的响应
+#[derive(Serialize)]
+pub struct ModelList {
+    object: &'static str,
+    data: Vec<ModelObject>,
+}
 
-/// 初始化系统状态
-pub fn initialize_system_status() {
-    unsafe {
-        START_TIME = Some(SystemTime::now());
-    }
+/// 请求没有指定 `max_tokens` 时为每个回复预留的token数
+const DEFAULT_COMPLETION_RESERVE: u32 = 1024;
+
+/// `n` 的上限，每个回复都是一次独立的上游请求
+const MAX_CHOICES: usize = 8;
+
+/// Prometheus格式的指标
+pub async fn get_metrics(Extension(metrics): Extension<SharedMetrics>) -> ProxyResult<Response> {
+    let body = metrics.render()?;
+    Ok(([(CONTENT_TYPE, "text/plain; version=0.0.4")], body).into_response())
 }
 
-/// 增加请求计数
-pub fn increment_request_count() {
-    unsafe {
-        TOTAL_REQUESTS += 1;
-    }
+/// 列出模型注册表中的模型
+pub async fn list_models(Extension(models): Extension<Arc<ModelRegistry>>) -> Json<ModelList> {
+    Json(ModelList {
+        object: "list",
+        data: models.list(),
+    })
 }
 
-/// 增加token计数
-pub fn add_tokens(tokens: u64) {
-    unsafe {
-        TOTAL_TOKENS += tokens;
-    }
+/// 按id或别名查询单个模型
+pub async fn get_model(
+    Extension(models): Extension<Arc<ModelRegistry>>,
+    Path(id): Path<String>,
+) -> ProxyResult<Json<ModelObject>> {
+    models
+        .get(&id)
+        .map(Json)
+        .ok_or_else(|| ProxyError::ModelNotFound(format!("The model '{}' does not exist", id)))
 }
 
-/// 状态页面接口
+/// 状态页面接口，启用API key认证时需要认证，只展示调用方自己的key用量
 pub async fn get_status(
     Extension(config): Extension<Arc<AppConfig>>,
     Extension(tracker): Extension<SharedRequestTracker>,
+    Extension(metrics): Extension<SharedMetrics>,
+    caller: Option<Extension<ClientIdentity>>,
 ) -> Json<SystemStatus> {
-    // 计算运行时间
-    let uptime = unsafe {
-        START_TIME.map_or(0, |start| {
-            SystemTime::now().duration_since(start).unwrap_or_default().as_secs()
-        })
-    };
     
-    // 获取活跃IP数量
-    let active_ips = {
-        let _tracker = tracker.lock().await;  // 添加下划线前缀忽略未使用变量警告
-        // 这里假设RequestTracker有一个方法来获取活跃IP数量
-        // 实际实现中可能需要添加此方法
-        0 // 暂时返回0，后续可以根据实际情况实现
-    };
+    // 获取活跃客户端数量
+    let active_clients = tracker.lock().await.active_clients();
     
-    // 获取统计数据
-    let stats = unsafe {
-        SystemStats {
-            total_requests: TOTAL_REQUESTS,
-            total_tokens: TOTAL_TOKENS,
-            active_ips,
-        }
+    // 统计数据与 /metrics 来自同一个指标注册表
+    let stats = SystemStats {
+        total_requests: metrics.total_requests(),
+        total_tokens: metrics.total_tokens(),
+        active_clients,
     };
     
     // 构建状态响应
     let status = SystemStatus {
         version: env!("CARGO_PKG_VERSION"),
-        uptime_seconds: uptime,
+        uptime_seconds: metrics.uptime().as_secs(),
         server_port: config.server_port,
         rate_limits: RateLimits {
             max_requests_per_minute: config.max_requests_per_minute,
             max_tokens_per_minute: config.max_tokens_per_minute,
         },
         stats,
+        api_keys: match caller {
+            Some(Extension(caller)) => middleware::api_key_usage(&tracker, &config, &caller).await,
+            None => Vec::new(),
+        },
     };
     
     Json(status)
 }
 
 /// 接收 /v1/chat/completions 的POST请求
+#[allow(clippy::too_many_arguments)]
 pub async fn chat_completion(
-    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Extension(ClientIp(addr)): Extension<ClientIp>,
     Extension(config): Extension<Arc<AppConfig>>,
     Extension(tracker): Extension<SharedRequestTracker>,
-    Json(payload): Json<ChatCompletionRequest>,
-) -> Json<ChatCompletionResponse> {
-    tracing::debug!("Received chat completion request from {}: {:?}", addr, payload);
-    
-    // 增加请求计数
-    increment_request_count();
-    
-    // 调用代理服务，向 ChatGPT 网页接口发起请求
-    let content_result = match proxy_service::send_to_chatgpt(&payload, config.clone()).await {
-        Ok(c) => c,
-        Err(e) => {
-            // 记录错误日志
-            tracing::error!("Error in chat completion from {}: {:#}", addr, e);
-            
-            // 处理错误，返回一个容错的response
-            let fallback_resp = ChatCompletionResponse {
-                id: format!("chatcmpl-{}", Uuid::new_v4()),
-                object: "chat.completion".to_string(),
-                created: current_timestamp(),
-                choices: vec![Choice {
-                    index: 0,
+    Extension(backends): Extension<Arc<BackendRouter>>,
+    Extension(models): Extension<Arc<ModelRegistry>>,
+    Extension(metrics): Extension<SharedMetrics>,
+    Extension(client): Extension<ClientIdentity>,
+    headers: HeaderMap,
+    payload: Result<Json<ChatCompletionRequest>, JsonRejection>,
+) -> Response {
+    let payload = payload.map_err(|e| ProxyError::BadRequest(e.body_text()));
+    let model = models.metric_label(payload.as_ref().map_or("", |Json(p)| p.model.as_str())).to_string();
+
+    let result: ProxyResult<Response> = async {
+        let Json(mut payload) = payload?;
+        if payload.messages.is_empty() {
+            return Err(ProxyError::BadRequest("'messages' must contain at least one message".to_string()));
+        }
+        if payload.has_images() && !config.image_attachments {
+            return Err(ProxyError::Unsupported(
+                "Image inputs are disabled on this proxy (IMAGE_ATTACHMENTS=false)".to_string(),
+            ));
+        }
+        if payload.has_image_links() && !config.image_url_fetch {
+            return Err(ProxyError::Unsupported(
+                "Image URLs are disabled on this proxy (IMAGE_URL_FETCH=false), send images as data: URLs".to_string(),
+            ));
+        }
+        tracing::debug!("Received chat completion request from {} ({}): {:?}", addr, client.id, payload);
+
+        payload.conversation_key = conversation_key(&client, &headers, &payload, &config);
+
+        let call =
+            UpstreamCall::start(&mut payload, &config, addr, client, tracker, metrics.clone(), &models, &backends)
+                .await?;
+
+        if payload.stream {
+            let id = format!("chatcmpl-{}", Uuid::new_v4());
+            let created = current_timestamp();
+            let model = payload.model.clone();
+            let chunk_event = move |index: usize, delta: Delta, finish_reason: Option<FinishReason>| {
+                let chunk = ChatCompletionChunk {
+                    id: id.clone(),
+                    object: "chat.completion.chunk".to_string(),
+                    created,
+                    model: model.clone(),
+                    choices: vec![ChunkChoice {
+                        index,
+                        delta,
+                        finish_reason: finish_reason.map(|r| r.as_str().to_string()),
+                    }],
+                };
+                Event::default().data(serde_json::to_string(&chunk).unwrap_or_default())
+            };
+
+            return call
+                .stream(payload, move |index, step| match step {
+                    StreamStep::Start => Some(chunk_event(
+                        index,
+                        Delta { role: Some("assistant".to_string()), ..Default::default() },
+                        None,
+                    )),
+                    StreamStep::Text(text) => {
+                        Some(chunk_event(index, Delta { content: Some(text), ..Default::default() }, None))
+                    }
+                    StreamStep::ToolCalls(calls) => {
+                        let calls = calls
+                            .into_iter()
+                            .enumerate()
+                            .map(|(index, call)| ToolCallDelta { index, call })
+                            .collect();
+                        Some(chunk_event(index, Delta { tool_calls: Some(calls), ..Default::default() }, None))
+                    }
+                    StreamStep::Finish(reason) => Some(chunk_event(index, Delta::default(), Some(reason))),
+                })
+                .await;
+        }
+
+        let (outputs, usage) = call.complete(&payload).await?;
+
+        // 构建OpenAI兼容格式的响应
+        let response = ChatCompletionResponse {
+            id: format!("chatcmpl-{}", Uuid::new_v4()),
+            object: "chat.completion".to_string(),
+            created: current_timestamp(),
+            choices: outputs
+                .into_iter()
+                .enumerate()
+                .map(|(index, output)| Choice {
+                    index,
                     message: MessageResponse {
                         role: "assistant".to_string(),
-                        content: format!("Error: {:#}", e),
+                        // 只有工具调用时content为null
+                        content: Some(output.text).filter(|text| !text.is_empty() || output.tool_calls.is_none()),
+                        tool_calls: output.tool_calls,
                     },
-                    finish_reason: "error".to_string(),
-                }],
-                usage: None,
+                    finish_reason: output.finish_reason.as_str().to_string(),
+                })
+                .collect(),
+            usage: Some(usage),
+        };
+
+        Ok(Json(response).into_response())
+    }
+    .await;
+
+    // 按模型和状态码统计请求
+    let response = result.into_response();
+    metrics.record_request(&model, response.status().as_u16());
+    response
+}
+
+/// 客户端提供会话id时复用上游会话；n > 1 时各个回复互相独立，不复用
+///
+/// 会话存储由所有客户端共用，id前加上客户端身份，不同API key使用相同的id时互不覆盖。
+fn conversation_key(
+    client: &ClientIdentity,
+    headers: &HeaderMap,
+    payload: &ChatCompletionRequest,
+    config: &AppConfig,
+) -> Option<String> {
+    headers
+        .get("x-conversation-id")
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string)
+        .or_else(|| payload.user.clone().filter(|_| config.conversation_key_from_user))
+        .filter(|_| payload.n.unwrap_or(1) == 1)
+        .map(|key| format!("{}:{}", client.id, key))
+}
+
+/// 接收 /v1/completions 的POST请求，把提示词转换为单轮对话后走与聊天接口相同的上游
+#[allow(clippy::too_many_arguments)]
+pub async fn text_completion(
+    Extension(ClientIp(addr)): Extension<ClientIp>,
+    Extension(config): Extension<Arc<AppConfig>>,
+    Extension(tracker): Extension<SharedRequestTracker>,
+    Extension(backends): Extension<Arc<BackendRouter>>,
+    Extension(models): Extension<Arc<ModelRegistry>>,
+    Extension(metrics): Extension<SharedMetrics>,
+    Extension(client): Extension<ClientIdentity>,
+    payload: Result<Json<CompletionRequest>, JsonRejection>,
+) -> Response {
+    let payload = payload.map_err(|e| ProxyError::BadRequest(e.body_text()));
+    let model = models.metric_label(payload.as_ref().map_or("", |Json(p)| p.model.as_str())).to_string();
+
+    let result: ProxyResult<Response> = async {
+        let Json(payload) = payload?;
+        tracing::debug!("Received text completion request from {} ({}): {:?}", addr, client.id, payload);
+
+        // echo 时返回的文本以提示词开头
+        let echo = if payload.echo { payload.prompt_text()?.to_string() } else { String::new() };
+        let mut chat_request = payload.to_chat_request()?;
+
+        let call =
+            UpstreamCall::start(&mut chat_request, &config, addr, client, tracker, metrics.clone(), &models, &backends)
+                .await?;
+
+        let id = format!("cmpl-{}", Uuid::new_v4());
+        let created = current_timestamp();
+
+        if chat_request.stream {
+            let model = chat_request.model.clone();
+            let chunk_event = move |index: usize, text: String, finish_reason: Option<FinishReason>| {
+                let chunk = TextCompletionResponse {
+                    id: id.clone(),
+                    object: "text_completion".to_string(),
+                    created,
+                    model: model.clone(),
+                    choices: vec![TextChoice {
+                        text,
+                        index,
+                        logprobs: None,
+                        finish_reason: finish_reason.map(|r| r.as_str().to_string()),
+                    }],
+                    usage: None,
+                };
+                Event::default().data(serde_json::to_string(&chunk).unwrap_or_default())
             };
-            return Json(fallback_resp);
+
+            return call
+                .stream(chat_request, move |index, step| match step {
+                    StreamStep::Start => (!echo.is_empty()).then(|| chunk_event(index, echo.clone(), None)),
+                    StreamStep::Text(text) => Some(chunk_event(index, text, None)),
+                    // 文本补全请求不带工具
+                    StreamStep::ToolCalls(_) => None,
+                    StreamStep::Finish(reason) => Some(chunk_event(index, String::new(), Some(reason))),
+                })
+                .await;
         }
-    };
 
-    // 估算token数量
-    let prompt_tokens = utils::estimate_token_count(&payload);
-    let completion_tokens = utils::estimate_token_count_str(&content_result);
-    let total_tokens = prompt_tokens + completion_tokens;
-    
-    // 增加token计数（转换为u64类型）
-    add_tokens(total_tokens as u64);
-    
-    // 记录token用量（用于限流）
-    let _ = middleware::record_token_usage(
-        addr.ip(), 
-        total_tokens as u32, 
-        tracker, 
-        config.clone()
-    ).await;
-    
-    // 构建OpenAI兼容格式的响应
-    let response = ChatCompletionResponse {
-        id: format!("chatcmpl-{}", Uuid::new_v4()),
-        object: "chat.completion".to_string(),
-        created: current_timestamp(),
-        choices: vec![
-            Choice {
-                index: 0,
-                message: MessageResponse {
-                    role: "assistant".to_string(),
-                    content: content_result,
-                },
-                finish_reason: "stop".to_string(),
+        let (outputs, usage) = call.complete(&chat_request).await?;
+
+        let response = TextCompletionResponse {
+            id,
+            object: "text_completion".to_string(),
+            created,
+            model: chat_request.model,
+            choices: outputs
+                .into_iter()
+                .enumerate()
+                .map(|(index, output)| TextChoice {
+                    text: echo.clone() + &output.text,
+                    index,
+                    logprobs: None,
+                    finish_reason: Some(output.finish_reason.as_str().to_string()),
+                })
+                .collect(),
+            usage: Some(usage),
+        };
+        Ok(Json(response).into_response())
+    }
+    .await;
+
+    let response = result.into_response();
+    metrics.record_request(&model, response.status().as_u16());
+    response
+}
+
+/// 流式响应中的一步，由各接口转换为自己格式的SSE事件
+enum StreamStep {
+    /// 开始输出之前
+    Start,
+    /// 新增的回复内容
+    Text(String),
+    /// 从回复中解析出的工具调用
+    ToolCalls(Vec<ToolCall>),
+    /// 回复结束及原因
+    Finish(FinishReason),
+}
+
+/// 一次上游调用：选定的后端、输出限制，以及结算token所需的信息
+struct UpstreamCall {
+    addr: IpAddr,
+    client: ClientIdentity,
+    user: Option<String>,
+    tracker: SharedRequestTracker,
+    metrics: SharedMetrics,
+    backend: Arc<dyn UpstreamBackend>,
+    reservation: TokenReservation,
+    prompt_tokens: i64,
+    choices: usize,
+    stops: Vec<String>,
+    max_tokens: Option<u32>,
+    tools: Option<ToolSet>,
+    format: Option<JsonFormat>,
+    repair_attempts: u32,
+}
+
+/// 一个完整回复经过处理后的结果
+struct Completion {
+    text: String,
+    tool_calls: Option<Vec<ToolCall>>,
+    finish_reason: FinishReason,
+}
+
+impl Completion {
+    /// 计入用量的内容：回复文本和工具调用的参数
+    fn billable_text(&self) -> String {
+        let mut text = self.text.clone();
+        for call in self.tool_calls.iter().flatten() {
+            text.push_str(&call.function.arguments);
+        }
+        text
+    }
+}
+
+/// 单个回复的输出处理：先识别工具调用块，其余文本再执行 `stop` 和 `max_tokens` 限制
+struct ChoiceFilter {
+    detector: Option<ToolCallDetector>,
+    limiter: OutputLimiter,
+}
+
+impl ChoiceFilter {
+    /// 输入新增文本，返回可以输出的部分；返回结束原因时之后的内容都应丢弃
+    fn push(&mut self, text: &str) -> (String, Option<FinishReason>) {
+        match &mut self.detector {
+            Some(detector) => self.limiter.push(&detector.push(text)),
+            None => self.limiter.push(text),
+        }
+    }
+
+    /// 上游输出结束，返回剩余的文本、解析出的工具调用和结束原因
+    fn finish(&mut self) -> (String, Option<Vec<ToolCall>>, FinishReason) {
+        match self.detector.as_mut().map(ToolCallDetector::finish) {
+            Some(Detected::ToolCalls(calls)) => {
+                let (rest, _) = self.limiter.finish();
+                (rest, Some(calls), FinishReason::ToolCalls)
+            }
+            // 无法解析的调用块作为普通文本输出
+            Some(Detected::Text(block)) => {
+                let (text, finish_reason) = self.limiter.finish_with(&block);
+                (text, None, finish_reason)
+            }
+            None => {
+                let (rest, finish_reason) = self.limiter.finish();
+                (rest, None, finish_reason)
             }
-        ],
-        usage: Some(Usage {
+        }
+    }
+
+    /// 处理完整回复
+    fn apply(mut self, content: &str) -> Completion {
+        let (mut text, finish_reason) = self.push(content);
+        if let Some(finish_reason) = finish_reason {
+            return Completion { text, tool_calls: None, finish_reason };
+        }
+        let (rest, tool_calls, finish_reason) = self.finish();
+        text.push_str(&rest);
+        Completion { text, tool_calls, finish_reason }
+    }
+}
+
+impl UpstreamCall {
+    /// 通过模型注册表确定上游模型名和后端，并按估算的提示词和 `n * max_tokens` 预留token，
+    /// 超过每分钟限制时直接返回429
+    #[allow(clippy::too_many_arguments)]
+    async fn start(
+        payload: &mut ChatCompletionRequest,
+        config: &AppConfig,
+        addr: IpAddr,
+        client: ClientIdentity,
+        tracker: SharedRequestTracker,
+        metrics: SharedMetrics,
+        models: &ModelRegistry,
+        backends: &BackendRouter,
+    ) -> ProxyResult<Self> {
+        let choices = payload.n.unwrap_or(1) as usize;
+        if !(1..=MAX_CHOICES).contains(&choices) {
+            return Err(ProxyError::BadRequest(format!("'n' must be between 1 and {}", MAX_CHOICES)));
+        }
+        let stops = payload.stop.as_ref().map(StopSequences::to_vec).transpose()?.unwrap_or_default();
+        // 工具调用和JSON输出格式在提示词中模拟，需要在估算token之前改写消息
+        let format = response_format::prepare(payload)?;
+        let tools = tool_calls::prepare(payload)?;
+
+        let resolved = models.resolve(&payload.model)?;
+        if let (Some(max_tokens), Some(context_window)) = (payload.max_tokens, resolved.context_window) {
+            if max_tokens > context_window {
+                return Err(ProxyError::BadRequest(format!(
+                    "'max_tokens' is {}, but the model '{}' has a context window of {} tokens",
+                    max_tokens, payload.model, context_window
+                )));
+            }
+        }
+        let backend = backends.select(&resolved);
+        tracing::debug!(
+            "Using backend {} for model {} (upstream {})",
+            backend.name(),
+            payload.model,
+            resolved.upstream
+        );
+        payload.upstream_model = Some(resolved.upstream);
+
+        let prompt_tokens = utils::estimate_token_count(payload);
+        let reserve = token_reservation(prompt_tokens, choices, payload.max_tokens);
+        let reservation = middleware::reserve_tokens(&client, reserve, &tracker).await.inspect_err(|_| {
+            metrics.record_rate_limit_rejection("tokens");
+        })?;
+
+        Ok(Self {
+            addr,
+            client,
+            user: payload.user.clone(),
+            tracker,
+            metrics,
+            backend,
+            reservation,
             prompt_tokens,
+            choices,
+            stops,
+            max_tokens: payload.max_tokens,
+            tools,
+            format,
+            repair_attempts: config.json_repair_attempts,
+        })
+    }
+
+    fn filter(&self) -> ChoiceFilter {
+        ChoiceFilter {
+            detector: self.tools.clone().map(ToolCallDetector::new),
+            limiter: OutputLimiter::new(self.stops.clone(), self.max_tokens),
+        }
+    }
+
+    /// 并行请求 `n` 个完整回复，解析工具调用并执行 `stop` 和 `max_tokens` 限制，按处理后的内容结算；
+    /// 任何一个失败时释放预留的token并返回错误
+    async fn complete(self, payload: &ChatCompletionRequest) -> ProxyResult<(Vec<Completion>, Usage)> {
+        let started = Instant::now();
+        let results = join_all((0..self.choices).map(|_| self.complete_choice(payload))).await;
+        self.metrics.observe_upstream_latency(self.backend.name(), started.elapsed());
+
+        match results.into_iter().collect::<ProxyResult<Vec<_>>>() {
+            Ok(outputs) => {
+                // 续接会话时按返回给客户端的回复保存，只有一个回复时才会续接
+                if let [output] = outputs.as_slice() {
+                    payload.conversation_turn.record(&output.text).await;
+                }
+                let completion: String = outputs.iter().map(Completion::billable_text).collect();
+                let usage = self.settle(&completion).await;
+                Ok((outputs, usage))
+            }
+            Err(e) => {
+                tracing::error!("Error in completion from {}: {}", self.addr, e);
+                self.abort().await;
+                Err(e)
+            }
+        }
+    }
+
+    /// 请求一个完整回复；要求JSON输出时校验回复，不符合时带着原因要求上游修正，
+    /// 超过 `JSON_REPAIR_ATTEMPTS` 次后返回错误
+    async fn complete_choice(&self, payload: &ChatCompletionRequest) -> ProxyResult<Completion> {
+        let content = self.backend.complete(payload).await?;
+        let mut output = self.filter().apply(&content);
+        let Some(format) = &self.format else {
+            return Ok(output);
+        };
+
+        // 修正请求带上完整的历史，不续接上游会话
+        let mut repair = payload.clone();
+        repair.conversation_key = None;
+        let mut reply = content;
+        let mut attempts = 0;
+        loop {
+            // 工具调用不受输出格式约束
+            if output.tool_calls.is_some() {
+                return Ok(output);
+            }
+            let reason = match format.check(&output.text) {
+                Ok(json) => {
+                    output.text = json;
+                    return Ok(output);
+                }
+                Err(reason) => reason,
+            };
+            if attempts >= self.repair_attempts {
+                return Err(ProxyError::InvalidOutput(format!(
+                    "The model did not produce valid JSON for 'response_format' after {} attempts: {}",
+                    attempts + 1,
+                    reason
+                )));
+            }
+
+            attempts += 1;
+            // 上游会话中保留的是未通过校验的回复，与返回给客户端的内容不一致，不再续接
+            payload.conversation_turn.forget().await;
+            tracing::warn!("Invalid JSON reply for {} ({}), asking for a repair", self.addr, reason);
+            repair.messages.push(Message::text("assistant", reply));
+            repair.messages.push(Message::text("user", format.repair_prompt(&reason)));
+            reply = self.backend.complete(&repair).await?;
+            output = self.filter().apply(&reply);
+        }
+    }
+
+    /// 用实际用量结算预留的token，返回响应中的usage
+    async fn settle(self, completion: &str) -> Usage {
+        let completion_tokens = utils::estimate_token_count_str(completion);
+        let total_tokens = self.prompt_tokens + completion_tokens;
+        self.metrics.record_tokens(self.prompt_tokens as u64, completion_tokens as u64);
+        middleware::settle_tokens(&self.client, self.reservation, total_tokens as u32, &self.tracker).await;
+
+        // 按API key和终端用户记录用量，便于追溯
+        tracing::info!(
+            "Completion for {} (user: {}) used {} tokens",
+            self.client.id,
+            self.user.as_deref().unwrap_or("-"),
+            total_tokens
+        );
+        Usage {
+            prompt_tokens: self.prompt_tokens,
             completion_tokens,
             total_tokens,
-        }),
-    };
+        }
+    }
 
-    tracing::debug!("Returning response to {} with {} tokens", addr, total_tokens);
-    Json(response)
+    /// 调用失败时释放预留的token
+    async fn abort(self) {
+        middleware::settle_tokens(&self.client, self.reservation, 0, &self.tracker).await;
+    }
+
+    /// 以SSE形式返回 `n` 个回复，以 `data: [DONE]` 结束；`render` 把每个回复的每一步转换为
+    /// 接口对应格式的事件。各回复分别执行 `stop` 和 `max_tokens` 限制
+    async fn stream<F>(self, payload: ChatCompletionRequest, render: F) -> ProxyResult<Response>
+    where
+        F: Fn(usize, StreamStep) -> Option<Event> + Send + Sync + 'static,
+    {
+        // JSON输出需要完整的回复才能校验，校验通过后一次性输出
+        if self.format.is_some() {
+            let (outputs, _) = self.complete(&payload).await?;
+            return Ok(replay(outputs, render));
+        }
+
+        // 上游在开始输出前失败时直接返回对应的HTTP错误
+        let started = Instant::now();
+        let results = join_all((0..self.choices).map(|_| self.backend.stream(&payload))).await;
+        self.metrics.observe_upstream_latency(self.backend.name(), started.elapsed());
+        let streams = match results.into_iter().collect::<ProxyResult<Vec<_>>>() {
+            Ok(streams) => streams,
+            Err(e) => {
+                tracing::error!("Error in streaming completion from {}: {}", self.addr, e);
+                self.abort().await;
+                return Err(e);
+            }
+        };
+
+        // 合并各回复的增量流，每个流结束时追加一个None
+        let mut deltas = stream::select_all(streams.into_iter().enumerate().map(|(index, deltas)| {
+            deltas
+                .map(move |delta| (index, Some(delta)))
+                .chain(stream::once(async move { (index, None) }))
+                .boxed()
+        }));
+        let (mut tx, rx) = futures::channel::mpsc::channel::<Event>(16);
+        let addr = self.addr;
+        let recorder = payload.conversation_turn.clone();
+        let record_turn = payload.conversation_key.is_some();
+
+        // 在后台任务中读取上游增量并转发，客户端断开时发送失败即停止读取
+        tokio::spawn(async move {
+            // 转发全部增量，返回计费的内容，以及完整发送给客户端时的回复文本；
+            // 客户端断开或上游出错时提前结束
+            let (content, reply) = async {
+                let mut content = String::new();
+                let mut reply = String::new();
+                let mut filters: Vec<_> = (0..self.choices).map(|_| Some(self.filter())).collect();
+
+                for index in 0..self.choices {
+                    if let Some(event) = render(index, StreamStep::Start) {
+                        if tx.send(event).await.is_err() {
+                            return (content, None);
+                        }
+                    }
+                }
+
+                while let Some((index, delta)) = deltas.next().await {
+                    // 已经结束的回复忽略后续增量
+                    let Some(filter) = filters[index].as_mut() else {
+                        continue;
+                    };
+                    let (text, tool_calls, finish_reason) = match delta {
+                        Some(Ok(text)) => {
+                            let (text, finish_reason) = filter.push(&text);
+                            (text, None, finish_reason)
+                        }
+                        Some(Err(e)) => {
+                            // 已经开始输出，只能以OpenAI格式的错误事件结束流
+                            tracing::error!("Upstream stream error for {}: {}", addr, e);
+                            let _ = tx.send(Event::default().data(e.to_body().to_string())).await;
+                            return (content, None);
+                        }
+                        // 上游输出结束，输出为判断停止序列和工具调用而保留的末尾内容
+                        None => {
+                            let (rest, tool_calls, finish_reason) = filter.finish();
+                            (rest, tool_calls, Some(finish_reason))
+                        }
+                    };
+
+                    if !text.is_empty() {
+                        content.push_str(&text);
+                        reply.push_str(&text);
+                        if let Some(event) = render(index, StreamStep::Text(text)) {
+                            if tx.send(event).await.is_err() {
+                                tracing::debug!("Client {} disconnected during streaming", addr);
+                                return (content, None);
+                            }
+                        }
+                    }
+                    if let Some(tool_calls) = tool_calls {
+                        tool_calls.iter().for_each(|call| content.push_str(&call.function.arguments));
+                        if let Some(event) = render(index, StreamStep::ToolCalls(tool_calls)) {
+                            let _ = tx.send(event).await;
+                        }
+                    }
+                    if let Some(finish_reason) = finish_reason {
+                        filters[index] = None;
+                        if let Some(event) = render(index, StreamStep::Finish(finish_reason)) {
+                            let _ = tx.send(event).await;
+                        }
+                        if filters.iter().all(Option::is_none) {
+                            break;
+                        }
+                    }
+                }
+
+                let _ = tx.send(Event::default().data("[DONE]")).await;
+                (content, Some(reply))
+            }
+            .await;
+
+            // 流结束后按实际输出结算token，提前结束时也要释放预留
+            self.settle(&content).await;
+            drop(tx);
+
+            // 续接会话时按返回给客户端的回复保存；因 `stop` 或 `max_tokens` 提前结束时，
+            // 客户端已经收到 [DONE]，继续读完上游的回复，拿到上游会话信息后再保存
+            if let Some(reply) = reply.filter(|_| record_turn) {
+                while deltas.next().await.is_some() {}
+                recorder.record(&reply).await;
+            }
+        });
+
+        Ok(Sse::new(rx.map(Ok::<_, Infallible>))
+            .keep_alive(KeepAlive::default())
+            .into_response())
+    }
+}
+
+/// 把已经完成的回复按流式步骤输出为SSE
+fn replay<F>(outputs: Vec<Completion>, render: F) -> Response
+where
+    F: Fn(usize, StreamStep) -> Option<Event>,
+{
+    let mut events = Vec::new();
+    for (index, output) in outputs.into_iter().enumerate() {
+        events.extend(render(index, StreamStep::Start));
+        if !output.text.is_empty() {
+            events.extend(render(index, StreamStep::Text(output.text)));
+        }
+        if let Some(tool_calls) = output.tool_calls {
+            events.extend(render(index, StreamStep::ToolCalls(tool_calls)));
+        }
+        events.extend(render(index, StreamStep::Finish(output.finish_reason)));
+    }
+    events.push(Event::default().data("[DONE]"));
+
+    Sse::new(stream::iter(events).map(Ok::<_, Infallible>)).into_response()
+}
+
+/// 请求需要预留的token：提示词加上 `n` 个回复的 `max_tokens`
+///
+/// `n` 和 `max_tokens` 由客户端决定，用u64饱和运算，避免溢出后预留量反而变小。
+fn token_reservation(prompt_tokens: i64, choices: usize, max_tokens: Option<u32>) -> u32 {
+    let completion = max_tokens.unwrap_or(DEFAULT_COMPLETION_RESERVE) as u64;
+    let reserve = (prompt_tokens.max(0) as u64).saturating_add((choices as u64).saturating_mul(completion));
+    u32::try_from(reserve).unwrap_or(u32::MAX)
 }
 
 /// 获取当前Unix时间戳(秒)
@@ -191,3 +770,372 @@ fn current_timestamp() -> i64 {
     let since_the_epoch = start.duration_since(UNIX_EPOCH).unwrap();
     since_the_epoch.as_secs() as i64
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::DeltaStream;
+    use crate::conversation_store::ConversationStore;
+    use crate::config::ApiKeyConfig;
+    use crate::metrics::Metrics;
+    use crate::middleware::{ClientId, ClientLimits};
+    use axum::body::HttpBody;
+    use std::time::Duration;
+
+    const TOKENS_PER_MINUTE: u32 = 10_000;
+
+    /// 每次调用都按顺序返回同样增量的后端，`fail` 时在这些增量之后返回上游错误；
+    /// 请求带会话id时像网页端后端一样记下上游会话
+    struct FixedBackend {
+        deltas: Vec<&'static str>,
+        fail: bool,
+        // 流式输出这些增量后既不结束也不出错，模拟仍在生成的上游
+        endless: bool,
+        conversations: Arc<ConversationStore>,
+    }
+
+    impl FixedBackend {
+        fn new(deltas: &[&'static str]) -> Self {
+            Self {
+                deltas: deltas.to_vec(),
+                fail: false,
+                endless: false,
+                conversations: Arc::new(ConversationStore::new(Duration::from_secs(60), 10)),
+            }
+        }
+
+        fn failing(deltas: &[&'static str]) -> Self {
+            Self { fail: true, ..Self::new(deltas) }
+        }
+
+        fn endless(deltas: &[&'static str]) -> Self {
+            Self { endless: true, ..Self::new(deltas) }
+        }
+    }
+
+    async fn answer(conversations: Arc<ConversationStore>, req: &ChatCompletionRequest) {
+        if let Some(key) = &req.conversation_key {
+            let turn = ConversationStore::begin_turn(key, &req.messages);
+            req.conversation_turn.answered(conversations, turn, "conv".to_string(), "reply".to_string()).await;
+        }
+    }
+
+    fn upstream_error() -> ProxyError {
+        ProxyError::UpstreamServer { status: 502, message: "bad gateway".to_string() }
+    }
+
+    #[async_trait::async_trait]
+    impl UpstreamBackend for FixedBackend {
+        fn name(&self) -> &str {
+            "fixed"
+        }
+
+        async fn complete(&self, req: &ChatCompletionRequest) -> ProxyResult<String> {
+            if self.fail {
+                return Err(upstream_error());
+            }
+            answer(self.conversations.clone(), req).await;
+            Ok(self.deltas.concat())
+        }
+
+        async fn stream(&self, req: &ChatCompletionRequest) -> ProxyResult<DeltaStream> {
+            let mut deltas: Vec<_> = self.deltas.iter().map(|delta| Ok(delta.to_string())).collect();
+            if self.fail {
+                deltas.push(Err(upstream_error()));
+                return Ok(Box::pin(stream::iter(deltas)));
+            }
+            if self.endless {
+                return Ok(Box::pin(stream::iter(deltas).chain(stream::pending())));
+            }
+            // 与网页端后端一样，读完整个流之后才记下上游会话
+            let (conversations, req) = (self.conversations.clone(), req.clone());
+            let answered = stream::once(async move { answer(conversations, &req).await }).filter_map(|()| async { None });
+            Ok(Box::pin(stream::iter(deltas).chain(answered)))
+        }
+    }
+
+    /// 处理函数需要的全部扩展，请求来自一个使用全局限额的API key
+    struct Harness {
+        config: Arc<AppConfig>,
+        tracker: SharedRequestTracker,
+        backends: Arc<BackendRouter>,
+        models: Arc<ModelRegistry>,
+        metrics: SharedMetrics,
+        client: ClientIdentity,
+        conversations: Arc<ConversationStore>,
+    }
+
+    impl Harness {
+        fn new(backend: FixedBackend) -> Self {
+            let mut config = AppConfig::for_tests(&[("MAX_TOKENS_PER_MINUTE", &TOKENS_PER_MINUTE.to_string())]);
+            config.api_keys.push(ApiKeyConfig {
+                name: "tests".to_string(),
+                key_sha256: middleware::hash_api_key("sk-tests"),
+                requests_per_minute: None,
+                tokens_per_minute: None,
+                daily_token_quota: None,
+                monthly_token_quota: None,
+            });
+            let client = ClientIdentity {
+                id: ClientId::ApiKey("tests".to_string()),
+                limits: ClientLimits {
+                    requests_per_minute: config.max_requests_per_minute,
+                    tokens_per_minute: TOKENS_PER_MINUTE,
+                    daily_token_quota: None,
+                    monthly_token_quota: None,
+                },
+            };
+            Self {
+                conversations: backend.conversations.clone(),
+                models: Arc::new(ModelRegistry::from_config(&config).unwrap()),
+                config: Arc::new(config),
+                tracker: middleware::create_request_tracker(),
+                backends: Arc::new(BackendRouter::single(Arc::new(backend))),
+                metrics: Arc::new(Metrics::new().unwrap()),
+                client,
+            }
+        }
+
+        async fn chat(&self, headers: HeaderMap, body: serde_json::Value) -> Response {
+            chat_completion(
+                Extension(ClientIp(IpAddr::from([127, 0, 0, 1]))),
+                Extension(self.config.clone()),
+                Extension(self.tracker.clone()),
+                Extension(self.backends.clone()),
+                Extension(self.models.clone()),
+                Extension(self.metrics.clone()),
+                Extension(self.client.clone()),
+                headers,
+                Ok(Json(serde_json::from_value(body).unwrap())),
+            )
+            .await
+        }
+
+        async fn text(&self, body: serde_json::Value) -> Response {
+            text_completion(
+                Extension(ClientIp(IpAddr::from([127, 0, 0, 1]))),
+                Extension(self.config.clone()),
+                Extension(self.tracker.clone()),
+                Extension(self.backends.clone()),
+                Extension(self.models.clone()),
+                Extension(self.metrics.clone()),
+                Extension(self.client.clone()),
+                Ok(Json(serde_json::from_value(body).unwrap())),
+            )
+            .await
+        }
+
+        /// 这个API key已结算的token和当前剩余的每分钟额度
+        async fn usage(&self) -> KeyUsage {
+            middleware::api_key_usage(&self.tracker, &self.config, &self.client).await.remove(0)
+        }
+    }
+
+    fn chat_request(stream: bool, extra: serde_json::Value) -> serde_json::Value {
+        let mut request = serde_json::json!({
+            "model": "gpt-3.5-turbo",
+            "messages": [{ "role": "user", "content": "Say hello" }],
+            "stream": stream,
+        });
+        request.as_object_mut().unwrap().extend(extra.as_object().unwrap().clone());
+        request
+    }
+
+    async fn body_text(response: Response) -> String {
+        let mut body = response.into_body();
+        let mut bytes = Vec::new();
+        while let Some(chunk) = body.data().await {
+            bytes.extend_from_slice(&chunk.unwrap());
+        }
+        String::from_utf8(bytes).unwrap()
+    }
+
+    /// SSE响应中每个事件的data
+    async fn sse_data(response: Response) -> Vec<String> {
+        body_text(response)
+            .await
+            .lines()
+            .filter_map(|line| line.strip_prefix("data:"))
+            .map(str::to_string)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn echo_prefixes_the_prompt_to_the_completion() {
+        let harness = Harness::new(FixedBackend::new(&[" brown", " fox"]));
+        let request = serde_json::json!({ "model": "gpt-3.5-turbo", "prompt": ["The quick"], "echo": true });
+
+        let response = harness.text(request.clone()).await;
+        assert_eq!(response.status(), 200);
+        let json: serde_json::Value = serde_json::from_str(&body_text(response).await).unwrap();
+        assert_eq!(json["object"], "text_completion");
+        assert_eq!(json["choices"][0]["text"], "The quick brown fox");
+        assert_eq!(json["choices"][0]["finish_reason"], "stop");
+
+        let mut request = request;
+        request["stream"] = true.into();
+        let events = sse_data(harness.text(request).await).await;
+        let texts: Vec<serde_json::Value> =
+            events[..events.len() - 1].iter().map(|e| serde_json::from_str::<serde_json::Value>(e).unwrap()).collect();
+        assert_eq!(texts[0]["choices"][0]["text"], "The quick");
+        let streamed: String = texts.iter().map(|e| e["choices"][0]["text"].as_str().unwrap()).collect();
+        assert_eq!(streamed, "The quick brown fox");
+        assert_eq!(texts.last().unwrap()["choices"][0]["finish_reason"], "stop");
+        assert_eq!(events.last().unwrap(), "[DONE]");
+    }
+
+    #[tokio::test]
+    async fn text_completion_rejects_prompt_batches() {
+        let harness = Harness::new(FixedBackend::new(&["unused"]));
+        let response = harness.text(serde_json::json!({ "model": "gpt-3.5-turbo", "prompt": ["a", "b"] })).await;
+        assert_eq!(response.status(), 400);
+        let json: serde_json::Value = serde_json::from_str(&body_text(response).await).unwrap();
+        assert_eq!(json["error"]["code"], "invalid_request");
+    }
+
+    #[test]
+    fn conversation_keys_are_scoped_to_the_client() {
+        let harness = Harness::new(FixedBackend::new(&[]));
+        let mut other = harness.client.clone();
+        other.id = ClientId::ApiKey("other".to_string());
+        let mut headers = HeaderMap::new();
+        headers.insert("x-conversation-id", "default".parse().unwrap());
+        let request = |body: serde_json::Value| -> ChatCompletionRequest { serde_json::from_value(body).unwrap() };
+        let payload = request(serde_json::json!({ "model": "gpt-3.5-turbo", "messages": [], "user": "u1" }));
+
+        let key = conversation_key(&harness.client, &headers, &payload, &harness.config);
+        assert_eq!(key.as_deref(), Some("key tests:default"));
+        assert_ne!(conversation_key(&other, &headers, &payload, &harness.config), key);
+
+        // user 字段只在开启 CONVERSATION_KEY_FROM_USER 时使用
+        assert_eq!(conversation_key(&harness.client, &HeaderMap::new(), &payload, &harness.config), None);
+        let config = AppConfig::for_tests(&[("CONVERSATION_KEY_FROM_USER", "true")]);
+        let key = conversation_key(&harness.client, &HeaderMap::new(), &payload, &config);
+        assert_eq!(key.as_deref(), Some("key tests:u1"));
+
+        let payload = request(serde_json::json!({ "model": "gpt-3.5-turbo", "messages": [], "n": 2 }));
+        assert_eq!(conversation_key(&harness.client, &headers, &payload, &harness.config), None);
+    }
+
+    #[tokio::test]
+    async fn conversations_continue_from_the_reply_the_client_received() {
+        for stream in [false, true] {
+            let harness = Harness::new(FixedBackend::new(&["Sure. Here", " it is.\n\nAnything else?"]));
+            let mut headers = HeaderMap::new();
+            headers.insert("x-conversation-id", "chat".parse().unwrap());
+            let request = serde_json::json!({
+                "model": "gpt-3.5-turbo",
+                "messages": [{ "role": "user", "content": "hi" }],
+                "stop": "\n\n",
+                "stream": stream,
+            });
+            let response = harness.chat(headers, request).await;
+            assert_eq!(response.status(), 200);
+            body_text(response).await;
+
+            // 客户端带回的是按 stop 截断后的回复；流式响应在后台任务中保存会话
+            let history = [
+                Message::text("user", "hi".to_string()),
+                Message::text("assistant", "Sure. Here it is.".to_string()),
+                Message::text("user", "thanks".to_string()),
+            ];
+            let mut continuation = None;
+            for _ in 0..50 {
+                continuation = harness.conversations.lookup("key tests:chat", &history).await;
+                if continuation.is_some() {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            assert_eq!(continuation.map(|c| c.new_messages_from), Some(2), "stream: {}", stream);
+        }
+    }
+
+    #[tokio::test]
+    async fn streamed_choices_finish_independently_before_done() {
+        let harness = Harness::new(FixedBackend::new(&["Hel", "lo EN", "D ignored"]));
+        let request = chat_request(true, serde_json::json!({ "n": 2, "stop": "END" }));
+        let events = sse_data(harness.chat(HeaderMap::new(), request).await).await;
+
+        assert_eq!(events.last().unwrap(), "[DONE]");
+        let chunks: Vec<serde_json::Value> =
+            events[..events.len() - 1].iter().map(|e| serde_json::from_str(e).unwrap()).collect();
+        // 两个回复先各自发出role，之后的增量可能交错
+        for (position, chunk) in chunks[..2].iter().enumerate() {
+            assert_eq!(chunk["choices"][0]["index"], position);
+            assert_eq!(chunk["choices"][0]["delta"]["role"], "assistant");
+        }
+        for index in 0..2 {
+            let choice: Vec<&serde_json::Value> =
+                chunks.iter().map(|c| &c["choices"][0]).filter(|c| c["index"] == index).collect();
+            let content: String = choice.iter().filter_map(|c| c["delta"]["content"].as_str()).collect();
+            assert_eq!(content, "Hello ", "choice {}", index);
+            let finishes: Vec<usize> = (0..choice.len()).filter(|&i| !choice[i]["finish_reason"].is_null()).collect();
+            assert_eq!(finishes, [choice.len() - 1], "choice {}", index);
+            assert_eq!(choice.last().unwrap()["finish_reason"], "stop");
+        }
+
+        // 按两个回复实际输出的内容结算，不足 n * max_tokens 预留的部分退回
+        let usage = harness.usage().await;
+        assert!(usage.tokens_today > 0);
+        assert!(usage.remaining_tokens as u64 + usage.tokens_today >= TOKENS_PER_MINUTE as u64);
+    }
+
+    #[tokio::test]
+    async fn failed_completions_refund_the_reservation() {
+        let harness = Harness::new(FixedBackend::failing(&[]));
+        let response = harness.chat(HeaderMap::new(), chat_request(false, serde_json::json!({ "n": 2 }))).await;
+        assert_eq!(response.status(), 502);
+        let json: serde_json::Value = serde_json::from_str(&body_text(response).await).unwrap();
+        assert_eq!(json["error"]["code"], "upstream_server_error");
+
+        let usage = harness.usage().await;
+        assert_eq!(usage.tokens_today, 0);
+        assert_eq!(usage.remaining_tokens, TOKENS_PER_MINUTE);
+    }
+
+    #[tokio::test]
+    async fn stream_errors_end_the_stream_and_settle_the_partial_reply() {
+        let harness = Harness::new(FixedBackend::failing(&["Hello"]));
+        let events = sse_data(harness.chat(HeaderMap::new(), chat_request(true, serde_json::json!({}))).await).await;
+
+        // 已经开始输出后以错误事件结束，不发送 [DONE]
+        assert_eq!(events.len(), 3, "{:?}", events);
+        let content: serde_json::Value = serde_json::from_str(&events[1]).unwrap();
+        assert_eq!(content["choices"][0]["delta"]["content"], "Hello");
+        let error: serde_json::Value = serde_json::from_str(&events[2]).unwrap();
+        assert_eq!(error["error"]["code"], "upstream_server_error");
+
+        let usage = harness.usage().await;
+        assert!(usage.tokens_today > 0);
+        assert!(usage.remaining_tokens as u64 + usage.tokens_today >= TOKENS_PER_MINUTE as u64);
+    }
+
+    #[tokio::test]
+    async fn client_disconnect_settles_the_reservation() {
+        let harness = Harness::new(FixedBackend::endless(&["Hello", " there"]));
+        let response = harness.chat(HeaderMap::new(), chat_request(true, serde_json::json!({}))).await;
+        assert_eq!(response.status(), 200);
+        drop(response);
+
+        // 上游一直没有结束，只有客户端断开后才会结算
+        let mut usage = harness.usage().await;
+        for _ in 0..50 {
+            if usage.tokens_today > 0 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            usage = harness.usage().await;
+        }
+        assert!(usage.tokens_today > 0);
+        assert!(usage.remaining_tokens as u64 + usage.tokens_today >= TOKENS_PER_MINUTE as u64);
+    }
+
+    #[test]
+    fn token_reservation_saturates_instead_of_wrapping() {
+        assert_eq!(token_reservation(100, 2, Some(50)), 200);
+        assert_eq!(token_reservation(100, 1, None), 100 + DEFAULT_COMPLETION_RESERVE);
+        assert_eq!(token_reservation(10, 1, Some(u32::MAX)), u32::MAX);
+        assert_eq!(token_reservation(10, 8, Some(536_870_912)), u32::MAX);
+    }
+}
@@ -1,21 +1,34 @@
 use axum::{Router, routing::{post, get}, Extension};
-use axum::extract::connect_info::ConnectInfo;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 use std::net::SocketAddr;
 use std::sync::Arc;
-use tokio::sync::Mutex;
 use std::time::Duration;
 use axum::http::Request;
 use std::path::Path;
 use std::env;
 
+mod attachments;
+mod backend;
 mod config;
+mod conversation_store;
+mod credentials;
+mod error;
 mod handlers;
+mod http_client;
+mod metrics;
+mod models;
 mod proxy_service;
 mod openai_types;
+mod prompt;
 mod utils;
 mod token_refresher;
 mod middleware;
+mod token_cache;
+mod tool_calls;
+mod sse_decoder;
+mod output_limits;
+mod response_format;
+mod reload;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
@@ -26,33 +39,93 @@ async fn main() -> anyhow::Result<()> {
         .with(tracing_subscriber::fmt::layer())
         .init();
 
-    // 2. 加载配置（.env）
-    // 检查当前目录下的 .env 文件
-    let env_path = Path::new(".env");
-    if env_path.exists() {
-        match dotenvy::from_path(env_path) {
-            Ok(_) => tracing::info!("Loaded .env file successfully"),
-            Err(e) => tracing::error!("Failed to load .env file: {}", e),
-        }
-    } else {
+    // 2. 加载配置，.env 中的值在加载配置时读取，不会覆盖进程已有的环境变量
+    if !Path::new(config::DOTENV_FILE).exists() {
         tracing::warn!(".env file not found in current directory");
     }
 
-    // 打印关键环境变量的状态（不打印具体值，仅检查是否存在）
-    tracing::info!("CHATGPT_SESSION_TOKEN exists: {}", env::var("CHATGPT_SESSION_TOKEN").is_ok());
-    tracing::info!("CHATGPT_AUTHORIZATION exists: {}", env::var("CHATGPT_AUTHORIZATION").is_ok());
-    
-    let config = Arc::new(config::AppConfig::from_env()?);
+    // 配置优先级：命令行 > 环境变量（含 .env） > 配置文件 > 默认值
+    let Some(cli) = config::CliArgs::parse(env::args().skip(1))? else {
+        println!("{}", config::USAGE);
+        return Ok(());
+    };
+    let config = Arc::new(config::AppConfig::load(&cli)?);
     let server_port = config.server_port;  // 提前获取端口号
-    tracing::info!("Configuration loaded successfully");
-    
-    // 3. 初始化系统状态追踪
-    handlers::initialize_system_status();
+    match &config.config_file {
+        Some(path) => tracing::info!("Configuration loaded from {} and environment", path.display()),
+        None => tracing::info!("Configuration loaded from environment"),
+    }
+
+    // --check-config 只检查配置（包括模型注册表），不启动服务
+    if cli.check_config {
+        let models = models::ModelRegistry::from_config(&config)?;
+        http_client::check_proxies(&config).await?;
+        println!(
+            "Configuration OK: port {}, {} models, {} OpenAI backends, {} API keys",
+            config.server_port,
+            models.list().len(),
+            config.openai_backends.len(),
+            config.api_keys.len()
+        );
+        return Ok(());
+    }
+
+    // 创建共享的上游HTTP客户端，所有上游请求复用同一个连接池
+    let upstream_client = http_client::build_upstream_client(&config)?;
+    tracing::info!("Upstream HTTP client initialized");
+
+    // 出站代理不可达时所有上游请求都会失败，启动时就明确报告出来
+    if let Err(e) = http_client::check_proxies(&config).await {
+        tracing::error!("{:#}", e);
+    }
+
+    // 会话复用存储，定期清理过期会话
+    let conversations = Arc::new(conversation_store::ConversationStore::new(
+        Duration::from_secs(config.conversation_ttl_secs),
+        config.conversation_max_entries,
+    ));
+    conversations.clone().start_eviction_task();
+
+    // 指标注册表，/metrics 和 /status 共用
+    let metrics = Arc::new(metrics::Metrics::new()?);
+
+    // 可替换的认证信息，上游轮换的会话令牌会写回这里并持久化
+    let credentials = Arc::new(credentials::CredentialsStore::load(&config));
+
+    // 访问令牌缓存，由请求路径和后台刷新任务共享
+    let token_cache = Arc::new(
+        token_cache::AccessTokenCache::new(
+            credentials.clone(),
+            upstream_client.clone(),
+            metrics.clone(),
+            Duration::from_secs(config.access_token_refresh_margin_secs),
+        )
+        .await,
+    );
+
+    // 模型注册表决定可用的模型及其上游模型名和后端；二者与配置一起在热重载时整体替换
+    let (reloader, state) = reload::ConfigReloader::new(
+        cli,
+        config.clone(),
+        upstream_client.clone(),
+        credentials,
+        token_cache.clone(),
+        conversations,
+    )?;
+    tracing::info!(
+        "Model registry loaded with {} models, passthrough {}",
+        state.current().models.list().len(),
+        if config.model_passthrough { "enabled" } else { "disabled" }
+    );
+    tracing::info!("Upstream backends initialized, default backend: {}", config.default_backend);
+
+    // 配置来源文件变化或收到SIGHUP时重新加载配置
+    Arc::new(reloader).start_watching();
+    tracing::info!("Watching {} configuration files for changes", config.source_files.len());
     
     // 4. 初始化Token刷新器
-    let refreshable_config = Arc::new(Mutex::new(config.as_ref().clone()));
     let token_refresher = Arc::new(
-        token_refresher::TokenRefresher::new(refreshable_config.clone())
+        token_refresher::TokenRefresher::new(token_cache)
             .with_check_interval(Duration::from_secs(60 * 60)) // 每小时检查一次
     );
     
@@ -62,25 +135,62 @@ async fn main() -> anyhow::Result<()> {
 
     // 5. 创建请求跟踪器用于速率限制
     let request_tracker = middleware::create_request_tracker();
+    middleware::start_eviction_task(request_tracker.clone());
     tracing::info!("Request rate limiter initialized");
 
-    // 6. 构建路由
-    let app = Router::new()
+    if config.api_keys.is_empty() {
+        tracing::warn!("API_KEYS_FILE not set, /v1 endpoints are open to anyone who can reach this port");
+    } else {
+        tracing::info!("API key authentication enabled with {} keys", config.api_keys.len());
+    }
+
+    // 6. 构建路由，/v1 下的接口和 /status 需要API key，/v1 在认证之后按客户端限流
+    // 中间件在每个请求开始时读取当前配置，热重载后立即生效
+    let auth_state = state.clone();
+    let status_auth_state = state.clone();
+    let ip_state = state.clone();
+    let limiter_state = state.clone();
+    let limiter_tracker = request_tracker.clone();
+    let limiter_metrics = metrics.clone();
+    let api_routes = Router::new()
         .route("/v1/chat/completions", post(handlers::chat_completion))
-        .route("/health", get(|| async { "OK" }))
+        .route("/v1/completions", post(handlers::text_completion))
+        .route("/v1/models", get(handlers::list_models))
+        .route("/v1/models/*id", get(handlers::get_model))
+        .route_layer(axum::middleware::from_fn(move |req: Request<axum::body::Body>, next| {
+            let tracker = limiter_tracker.clone();
+            let config = limiter_state.current().config.clone();
+            let metrics = limiter_metrics.clone();
+            async move { middleware::rate_limiter(req, next, tracker, config, metrics).await }
+        }))
+        .route_layer(axum::middleware::from_fn(move |req: Request<axum::body::Body>, next| {
+            let config = auth_state.current().config.clone();
+            async move { middleware::api_key_auth(req, next, config).await }
+        }));
+
+    // /status 与 /v1 使用同样的认证，但不计入限流
+    let status_routes = Router::new()
         .route("/status", get(handlers::get_status))
-        .layer(Extension(config.clone()))
+        .route_layer(axum::middleware::from_fn(move |req: Request<axum::body::Body>, next| {
+            let config = status_auth_state.current().config.clone();
+            async move { middleware::api_key_auth(req, next, config).await }
+        }));
+
+    let app = Router::new()
+        .merge(api_routes)
+        .merge(status_routes)
+        .route("/health", get(|| async { "OK" }))
+        .route("/metrics", get(handlers::get_metrics))
         .layer(Extension(request_tracker.clone()))
-        .layer(tower::ServiceBuilder::new()
-            .layer(axum::middleware::from_fn(move |req: Request<axum::body::Body>, next| {
-                let tracker = request_tracker.clone();
-                let config = config.clone();
-                async move {
-                    let connect_info = req.extensions().get::<ConnectInfo<SocketAddr>>().cloned().unwrap();
-                    middleware::rate_limiter(connect_info, req, next, tracker, config).await
-                }
-            }))
-        );
+        .layer(Extension(upstream_client))
+        .layer(Extension(metrics))
+        .layer(axum::middleware::from_fn(move |req: Request<axum::body::Body>, next| {
+            let config = ip_state.current().config.clone();
+            async move { middleware::resolve_client_ip(req, next, config).await }
+        }))
+        .layer(axum::middleware::from_fn(move |req, next| {
+            reload::inject_state(req, next, state.clone())
+        }));
 
     // 7. 启动服务器
     let addr = SocketAddr::from(([0, 0, 0, 0], server_port));
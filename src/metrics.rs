@@ -0,0 +1,122 @@
+use anyhow::Result;
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounterVec, Opts, Registry, TextEncoder,
+};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// 代理的运行指标，`/metrics` 和 `/status` 都从这里读取
+pub struct Metrics {
+    registry: Registry,
+    started_at: Instant,
+    // 按模型和响应状态码统计的 /v1 请求数
+    requests: IntCounterVec,
+    // 按后端统计的上游响应时间（流式请求为收到响应头的时间）
+    upstream_latency: HistogramVec,
+    // 估算的token数，direction 为 prompt / completion
+    tokens: IntCounterVec,
+    // 被限流拒绝的请求，reason 为 requests / tokens / quota
+    rate_limit_rejections: IntCounterVec,
+    // 访问令牌刷新结果，outcome 为 success / failure
+    token_refreshes: IntCounterVec,
+}
+
+pub type SharedMetrics = Arc<Metrics>;
+
+impl Metrics {
+    pub fn new() -> Result<Self> {
+        let registry = Registry::new_custom(Some("chatgpt_proxy".to_string()), None)?;
+
+        let requests = IntCounterVec::new(
+            Opts::new("requests_total", "Requests handled on /v1 endpoints"),
+            &["model", "status"],
+        )?;
+        let upstream_latency = HistogramVec::new(
+            HistogramOpts::new("upstream_latency_seconds", "Time until the upstream backend responded")
+                .buckets(vec![0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0]),
+            &["backend"],
+        )?;
+        let tokens = IntCounterVec::new(
+            Opts::new("tokens_total", "Estimated tokens processed"),
+            &["direction"],
+        )?;
+        let rate_limit_rejections = IntCounterVec::new(
+            Opts::new("rate_limit_rejections_total", "Requests rejected by the rate limiter"),
+            &["reason"],
+        )?;
+        let token_refreshes = IntCounterVec::new(
+            Opts::new("token_refreshes_total", "Access token refresh attempts"),
+            &["outcome"],
+        )?;
+
+        registry.register(Box::new(requests.clone()))?;
+        registry.register(Box::new(upstream_latency.clone()))?;
+        registry.register(Box::new(tokens.clone()))?;
+        registry.register(Box::new(rate_limit_rejections.clone()))?;
+        registry.register(Box::new(token_refreshes.clone()))?;
+
+        Ok(Self {
+            registry,
+            started_at: Instant::now(),
+            requests,
+            upstream_latency,
+            tokens,
+            rate_limit_rejections,
+            token_refreshes,
+        })
+    }
+
+    pub fn record_request(&self, model: &str, status: u16) {
+        self.requests.with_label_values(&[model, &status.to_string()]).inc();
+    }
+
+    pub fn observe_upstream_latency(&self, backend: &str, elapsed: Duration) {
+        self.upstream_latency.with_label_values(&[backend]).observe(elapsed.as_secs_f64());
+    }
+
+    pub fn record_tokens(&self, prompt: u64, completion: u64) {
+        self.tokens.with_label_values(&["prompt"]).inc_by(prompt);
+        self.tokens.with_label_values(&["completion"]).inc_by(completion);
+    }
+
+    pub fn record_rate_limit_rejection(&self, reason: &str) {
+        self.rate_limit_rejections.with_label_values(&[reason]).inc();
+    }
+
+    pub fn record_token_refresh(&self, success: bool) {
+        let outcome = if success { "success" } else { "failure" };
+        self.token_refreshes.with_label_values(&[outcome]).inc();
+    }
+
+    pub fn uptime(&self) -> Duration {
+        self.started_at.elapsed()
+    }
+
+    /// 所有模型、所有状态码的请求总数
+    pub fn total_requests(&self) -> u64 {
+        sum_counter(&self.requests)
+    }
+
+    pub fn total_tokens(&self) -> u64 {
+        sum_counter(&self.tokens)
+    }
+
+    /// Prometheus文本格式
+    pub fn render(&self) -> Result<String> {
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        Ok(String::from_utf8(buffer)?)
+    }
+}
+
+/// 计数器所有标签组合的合计
+fn sum_counter(counter: &IntCounterVec) -> u64 {
+    use prometheus::core::Collector;
+
+    counter
+        .collect()
+        .iter()
+        .flat_map(|family| family.get_metric())
+        .map(|metric| metric.get_counter().get_value() as u64)
+        .sum()
+}
@@ -1,76 +1,383 @@
 use std::sync::Arc;
 use std::collections::HashMap;
-use std::time::{Duration, Instant};
-use std::net::IpAddr;
+use std::fmt;
+use std::time::{Duration, Instant, SystemTime};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 
 use axum::{
     extract::ConnectInfo,
-    http::{Request, StatusCode},
+    http::{header::AUTHORIZATION, HeaderMap, HeaderName, HeaderValue, Request},
     middleware::Next,
-    response::Response,
+    response::{IntoResponse, Response},
 };
+use ipnet::IpNet;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
 use tokio::sync::Mutex;
 
-use crate::config::AppConfig;
+use crate::config::{ApiKeyConfig, AppConfig};
+use crate::error::ProxyError;
+use crate::metrics::SharedMetrics;
+use crate::utils;
 
-// 用于跟踪IP地址请求数量的结构
+/// 限流和配额的统计对象：通过认证的API key，未启用认证时退回按IP统计
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ClientId {
+    ApiKey(String),
+    Ip(IpAddr),
+}
+
+impl fmt::Display for ClientId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::ApiKey(name) => write!(f, "key {}", name),
+            Self::Ip(ip) => write!(f, "IP {}", ip),
+        }
+    }
+}
+
+/// 单个客户端的限额，API key未单独配置的项使用全局设置
+#[derive(Debug, Clone, Serialize)]
+pub struct ClientLimits {
+    pub requests_per_minute: u32,
+    pub tokens_per_minute: u32,
+    pub daily_token_quota: Option<u64>,
+    pub monthly_token_quota: Option<u64>,
+}
+
+/// 发起请求的客户端，写入请求扩展供后续处理使用
+#[derive(Debug, Clone)]
+pub struct ClientIdentity {
+    pub id: ClientId,
+    pub limits: ClientLimits,
+}
+
+impl ClientIdentity {
+    fn from_api_key(key: &ApiKeyConfig, config: &AppConfig) -> Self {
+        Self {
+            id: ClientId::ApiKey(key.name.clone()),
+            limits: ClientLimits {
+                requests_per_minute: key.requests_per_minute.unwrap_or(config.max_requests_per_minute),
+                tokens_per_minute: key.tokens_per_minute.unwrap_or(config.max_tokens_per_minute),
+                daily_token_quota: key.daily_token_quota,
+                monthly_token_quota: key.monthly_token_quota,
+            },
+        }
+    }
+
+    fn from_ip(ip: IpAddr, config: &AppConfig) -> Self {
+        Self {
+            id: ClientId::Ip(ip),
+            limits: ClientLimits {
+                requests_per_minute: config.max_requests_per_minute,
+                tokens_per_minute: config.max_tokens_per_minute,
+                daily_token_quota: None,
+                monthly_token_quota: None,
+            },
+        }
+    }
+}
+
+/// API key按UTC自然日/自然月累计的token用量
+#[derive(Debug, Default)]
+struct QuotaUsage {
+    day: u64,
+    day_tokens: u64,
+    month: u32,
+    month_tokens: u64,
+}
+
+impl QuotaUsage {
+    /// 进入新的一天或新的一个月时清零对应的计数
+    fn roll_over(&mut self, day: u64, month: u32) {
+        if self.day != day {
+            self.day = day;
+            self.day_tokens = 0;
+        }
+        if self.month != month {
+            self.month = month;
+            self.month_tokens = 0;
+        }
+    }
+}
+
+/// 限流周期，限额均以每分钟计
+const RATE_PERIOD: Duration = Duration::from_secs(60);
+
+/// GCRA限流状态
+///
+/// 只保存理论到达时间（TAT）：每消耗一个单位，TAT向后推移 `周期 / 限额`，
+/// TAT超出当前时间一个周期以上时拒绝。允许的突发量等于每分钟限额，但不会像固定窗口那样在窗口边界出现两倍突发。
+#[derive(Debug, Clone, Copy)]
+struct Gcra {
+    tat: Instant,
+}
+
+impl Gcra {
+    fn new(now: Instant) -> Self {
+        Self { tat: now }
+    }
+
+    fn interval(limit: u32) -> Duration {
+        RATE_PERIOD / limit.max(1)
+    }
+
+    /// 尝试消耗 `cost` 个单位，失败时返回需要等待的时间
+    fn try_acquire(&mut self, now: Instant, cost: u32, limit: u32) -> Result<(), Duration> {
+        let new_tat = self.tat.max(now) + Self::interval(limit).saturating_mul(cost);
+        let backlog = new_tat.saturating_duration_since(now);
+        if backlog > RATE_PERIOD {
+            return Err(backlog - RATE_PERIOD);
+        }
+        self.tat = new_tat;
+        Ok(())
+    }
+
+    /// 不检查限额直接消耗，用于结算超出预留的用量
+    fn charge(&mut self, now: Instant, cost: u32, limit: u32) {
+        self.tat = self.tat.max(now) + Self::interval(limit).saturating_mul(cost);
+    }
+
+    /// 退还多预留的单位
+    fn refund(&mut self, now: Instant, cost: u32, limit: u32) {
+        self.tat = self
+            .tat
+            .checked_sub(Self::interval(limit).saturating_mul(cost))
+            .map_or(now, |tat| tat.max(now));
+    }
+
+    /// 当前还能立即消耗的单位数
+    fn remaining(&self, now: Instant, limit: u32) -> u32 {
+        let backlog = self.tat.saturating_duration_since(now);
+        let used = backlog.as_nanos().div_ceil(Self::interval(limit).as_nanos().max(1));
+        limit.saturating_sub(used.min(u32::MAX as u128) as u32)
+    }
+
+    /// 恢复到满额还需要的时间，为0时该状态与新客户端没有区别
+    fn reset_after(&self, now: Instant) -> Duration {
+        self.tat.saturating_duration_since(now)
+    }
+}
+
+/// 单个客户端的限流状态，大小固定
+#[derive(Debug, Clone, Copy)]
+struct ClientState {
+    requests: Gcra,
+    tokens: Gcra,
+}
+
+impl ClientState {
+    fn new(now: Instant) -> Self {
+        Self {
+            requests: Gcra::new(now),
+            tokens: Gcra::new(now),
+        }
+    }
+
+    fn is_idle(&self, now: Instant) -> bool {
+        self.requests.reset_after(now).is_zero() && self.tokens.reset_after(now).is_zero()
+    }
+}
+
+/// `/status` 中展示的单个API key用量
+#[derive(Debug, Serialize)]
+pub struct KeyUsage {
+    pub name: String,
+    pub remaining_requests: u32,
+    pub remaining_tokens: u32,
+    pub tokens_today: u64,
+    pub tokens_this_month: u64,
+    pub limits: ClientLimits,
+}
+
+// 按客户端跟踪请求频率、token用量和配额的结构
 pub struct RequestTracker {
-    requests: HashMap<IpAddr, Vec<Instant>>,
-    token_usage: HashMap<IpAddr, (Instant, u32)>, // (上次计数时间, token数量)
+    clients: HashMap<ClientId, ClientState>,
+    quotas: HashMap<String, QuotaUsage>, // API key名称 -> 配额用量
 }
 
 impl RequestTracker {
     fn new() -> Self {
         Self {
-            requests: HashMap::new(),
-            token_usage: HashMap::new(),
-        }
-    }
-
-    // 检查请求频率并记录
-    fn check_and_record_request(&mut self, ip: IpAddr, config: &AppConfig, now: Instant) -> bool {
-        // 清理过期的请求记录
-        let window = Duration::from_secs(60); // 1分钟窗口
-        
-        // 获取或创建该IP的请求记录
-        let requests = self.requests.entry(ip).or_insert_with(Vec::new);
-        
-        // 删除1分钟前的记录
-        requests.retain(|&time| now.duration_since(time) < window);
-        
-        // 检查是否超过限制
-        if requests.len() >= config.max_requests_per_minute as usize {
-            return false;
-        }
-        
-        // 记录本次请求
-        requests.push(now);
-        true
-    }
-    
-    // 记录token用量
-    fn record_token_usage(&mut self, ip: IpAddr, tokens: u32, config: &AppConfig, now: Instant) -> bool {
-        let window = Duration::from_secs(60); // 1分钟窗口
-        
-        // 获取或创建该IP的token用量记录
-        let (last_time, token_count) = self.token_usage.entry(ip).or_insert((now, 0));
-        
-        // 如果已经过了一分钟，重置计数
-        if now.duration_since(*last_time) >= window {
-            *last_time = now;
-            *token_count = tokens;
-            return true;
-        }
-        
-        // 检查是否超过限制
-        if *token_count + tokens > config.max_tokens_per_minute {
-            return false;
-        }
-        
-        // 累加token用量
-        *token_count += tokens;
-        true
+            clients: HashMap::new(),
+            quotas: HashMap::new(),
+        }
+    }
+
+    fn client_state(&mut self, id: &ClientId, now: Instant) -> &mut ClientState {
+        self.clients.entry(id.clone()).or_insert_with(|| ClientState::new(now))
+    }
+
+    // 检查请求频率和配额并记录
+    fn check_and_record_request(&mut self, client: &ClientIdentity, now: Instant) -> Result<(), ProxyError> {
+        if let ClientId::ApiKey(name) = &client.id {
+            self.check_quota(name, &client.limits)?;
+        }
+
+        let limit = client.limits.requests_per_minute;
+        self.client_state(&client.id, now)
+            .requests
+            .try_acquire(now, 1, limit)
+            .map_err(|retry_after| ProxyError::RateLimited {
+                message: format!(
+                    "Rate limit reached for requests: limit {} per minute, retry after {:.1}s",
+                    limit,
+                    retry_after.as_secs_f64()
+                ),
+                retry_after,
+            })
+    }
+
+    // 检查API key的每日/每月配额是否已用完
+    fn check_quota(&mut self, name: &str, limits: &ClientLimits) -> Result<(), ProxyError> {
+        let (day, month) = utils::utc_day_and_month(SystemTime::now());
+        let usage = self.quotas.entry(name.to_string()).or_default();
+        usage.roll_over(day, month);
+
+        if limits.daily_token_quota.is_some_and(|quota| usage.day_tokens >= quota) {
+            return Err(ProxyError::QuotaExceeded("Daily token quota exceeded for this API key".to_string()));
+        }
+        if limits.monthly_token_quota.is_some_and(|quota| usage.month_tokens >= quota) {
+            return Err(ProxyError::QuotaExceeded("Monthly token quota exceeded for this API key".to_string()));
+        }
+        Ok(())
+    }
+
+    // 转发请求前预留token，超过每分钟token限制时拒绝
+    //
+    // 超过每分钟限额的预留永远无法满足，重试也没有用，因此最多预留一分钟的限额，
+    // 超出的部分在结算时按实际用量扣除。
+    fn reserve_tokens(&mut self, client: &ClientIdentity, tokens: u32, now: Instant) -> Result<TokenReservation, ProxyError> {
+        let limit = client.limits.tokens_per_minute;
+        let tokens = tokens.min(limit);
+        let state = self.client_state(&client.id, now);
+        let remaining = state.tokens.remaining(now, limit);
+
+        state.tokens.try_acquire(now, tokens, limit).map_err(|retry_after| ProxyError::RateLimited {
+            message: format!(
+                "Rate limit reached for tokens: limit {} per minute, remaining {}, requested {}",
+                limit, remaining, tokens
+            ),
+            retry_after,
+        })?;
+        Ok(TokenReservation { tokens })
+    }
+
+    // 请求结束后用实际用量替换预留，并计入配额
+    fn settle_tokens(&mut self, client: &ClientIdentity, reservation: TokenReservation, used: u32, now: Instant) {
+        if let ClientId::ApiKey(name) = &client.id {
+            let (day, month) = utils::utc_day_and_month(SystemTime::now());
+            let usage = self.quotas.entry(name.clone()).or_default();
+            usage.roll_over(day, month);
+            usage.day_tokens += used as u64;
+            usage.month_tokens += used as u64;
+        }
+
+        let limit = client.limits.tokens_per_minute;
+        let state = self.client_state(&client.id, now);
+        if used >= reservation.tokens {
+            state.tokens.charge(now, used - reservation.tokens, limit);
+        } else {
+            state.tokens.refund(now, reservation.tokens - used, limit);
+        }
     }
+
+    /// 某个API key当前的用量
+    fn key_usage(&self, name: &str, limits: &ClientLimits, now: Instant) -> KeyUsage {
+        let (day, month) = utils::utc_day_and_month(SystemTime::now());
+        let state = self.clients.get(&ClientId::ApiKey(name.to_string()));
+        let quota = self.quotas.get(name);
+
+        KeyUsage {
+            name: name.to_string(),
+            remaining_requests: state.map_or(limits.requests_per_minute, |s| {
+                s.requests.remaining(now, limits.requests_per_minute)
+            }),
+            remaining_tokens: state.map_or(limits.tokens_per_minute, |s| {
+                s.tokens.remaining(now, limits.tokens_per_minute)
+            }),
+            tokens_today: quota.filter(|q| q.day == day).map_or(0, |q| q.day_tokens),
+            tokens_this_month: quota.filter(|q| q.month == month).map_or(0, |q| q.month_tokens),
+            limits: limits.clone(),
+        }
+    }
+
+    /// 客户端当前的限额、剩余额度和恢复时间
+    fn rate_limit_status(&self, client: &ClientIdentity, now: Instant) -> RateLimitStatus {
+        let state = self.clients.get(&client.id).copied().unwrap_or_else(|| ClientState::new(now));
+        let limits = &client.limits;
+        RateLimitStatus {
+            limit_requests: limits.requests_per_minute,
+            remaining_requests: state.requests.remaining(now, limits.requests_per_minute),
+            reset_requests: state.requests.reset_after(now),
+            limit_tokens: limits.tokens_per_minute,
+            remaining_tokens: state.tokens.remaining(now, limits.tokens_per_minute),
+            reset_tokens: state.tokens.reset_after(now),
+        }
+    }
+
+    /// 限流状态尚未恢复满额的客户端数量
+    pub fn active_clients(&self) -> usize {
+        let now = Instant::now();
+        self.clients.values().filter(|state| !state.is_idle(now)).count()
+    }
+
+    /// 删除已恢复满额的客户端状态，删除后再次出现时与原状态等价
+    fn evict_idle(&mut self, now: Instant) -> usize {
+        let before = self.clients.len();
+        self.clients.retain(|_, state| !state.is_idle(now));
+        before - self.clients.len()
+    }
+}
+
+/// 附加在每个 `/v1` 响应上的限流状态，格式与OpenAI的 `x-ratelimit-*` 响应头一致
+#[derive(Debug)]
+struct RateLimitStatus {
+    limit_requests: u32,
+    remaining_requests: u32,
+    reset_requests: Duration,
+    limit_tokens: u32,
+    remaining_tokens: u32,
+    reset_tokens: Duration,
+}
+
+impl RateLimitStatus {
+    fn apply(&self, headers: &mut HeaderMap) {
+        let values = [
+            ("x-ratelimit-limit-requests", self.limit_requests.to_string()),
+            ("x-ratelimit-remaining-requests", self.remaining_requests.to_string()),
+            ("x-ratelimit-reset-requests", format_reset(self.reset_requests)),
+            ("x-ratelimit-limit-tokens", self.limit_tokens.to_string()),
+            ("x-ratelimit-remaining-tokens", self.remaining_tokens.to_string()),
+            ("x-ratelimit-reset-tokens", format_reset(self.reset_tokens)),
+        ];
+        for (name, value) in values {
+            if let Ok(value) = HeaderValue::from_str(&value) {
+                headers.insert(HeaderName::from_static(name), value);
+            }
+        }
+    }
+}
+
+/// 按OpenAI的格式输出恢复时间，例如 `20ms`、`1.5s`、`6m0s`
+fn format_reset(duration: Duration) -> String {
+    let millis = duration.as_millis();
+    if millis < 1000 {
+        format!("{}ms", millis)
+    } else if millis < 60_000 {
+        let secs = format!("{:.3}", duration.as_secs_f64());
+        format!("{}s", secs.trim_end_matches('0').trim_end_matches('.'))
+    } else {
+        let secs = duration.as_secs();
+        format!("{}m{}s", secs / 60, secs % 60)
+    }
+}
+
+/// 转发请求前预留的token，请求结束后必须通过 `settle_tokens` 结算
+#[must_use]
+#[derive(Debug)]
+pub struct TokenReservation {
+    tokens: u32,
 }
 
 // 创建一个全局请求跟踪器
@@ -80,40 +387,449 @@ pub fn create_request_tracker() -> SharedRequestTracker {
     Arc::new(Mutex::new(RequestTracker::new()))
 }
 
-// 使用异步中间件处理函数的方式定义中间件
+/// 启动定期清理空闲客户端限流状态的后台任务
+pub fn start_eviction_task(tracker: SharedRequestTracker) {
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(RATE_PERIOD).await;
+
+            let evicted = tracker.lock().await.evict_idle(Instant::now());
+            if evicted > 0 {
+                tracing::debug!("Evicted {} idle rate limiter entries", evicted);
+            }
+        }
+    });
+}
+
+/// 调用方自己的API key用量，供 `/status` 展示；不会列出其他key的名称和用量
+pub async fn api_key_usage(
+    tracker: &SharedRequestTracker,
+    config: &AppConfig,
+    caller: &ClientIdentity,
+) -> Vec<KeyUsage> {
+    let ClientId::ApiKey(name) = &caller.id else {
+        return Vec::new();
+    };
+    let now = Instant::now();
+    let tracker = tracker.lock().await;
+    config
+        .api_keys
+        .iter()
+        .filter(|key| &key.name == name)
+        .map(|key| tracker.key_usage(&key.name, &ClientIdentity::from_api_key(key, config).limits, now))
+        .collect()
+}
+
+/// 请求的真实客户端IP，由 `resolve_client_ip` 写入请求扩展
+#[derive(Debug, Clone, Copy)]
+pub struct ClientIp(pub IpAddr);
+
+/// 确定客户端IP：对端是可信代理时从转发头读取，否则使用对端地址
+pub async fn resolve_client_ip<B>(mut req: Request<B>, next: Next<B>, config: Arc<AppConfig>) -> Response {
+    let peer = req.extensions().get::<ConnectInfo<SocketAddr>>().map(|ConnectInfo(addr)| addr.ip());
+    let ip = match client_ip(peer, req.headers(), &config.trusted_proxies) {
+        Some(ip) => ip,
+        None => {
+            tracing::warn!("Request without connection info, client IP is unknown");
+            IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+        }
+    };
+    req.extensions_mut().insert(ClientIp(ip));
+    next.run(req).await
+}
+
+/// 根据对端地址和转发头计算客户端IP
+///
+/// 依次查看 `X-Forwarded-For`、`Forwarded`、`X-Real-IP`，从右向左跳过可信代理，
+/// 第一个不可信的地址即为客户端；遇到无法解析的地址时不再相信转发头，退回对端地址。
+fn client_ip(peer: Option<IpAddr>, headers: &HeaderMap, trusted_proxies: &[IpNet]) -> Option<IpAddr> {
+    let is_trusted = |ip: &IpAddr| trusted_proxies.iter().any(|net| net.contains(ip));
+    let peer = peer?;
+    if !is_trusted(&peer) {
+        return Some(peer);
+    }
+
+    let header_values = |name: &str| -> Vec<String> {
+        headers
+            .get_all(name)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .map(str::to_string)
+            .collect()
+    };
+
+    let chain: Vec<Option<IpAddr>> = if headers.contains_key("x-forwarded-for") {
+        header_values("x-forwarded-for")
+            .iter()
+            .flat_map(|v| v.split(','))
+            .map(parse_node)
+            .collect()
+    } else if headers.contains_key("forwarded") {
+        header_values("forwarded")
+            .iter()
+            .flat_map(|v| v.split(','))
+            .filter_map(|element| {
+                element
+                    .split(';')
+                    .filter_map(|pair| pair.split_once('='))
+                    .find(|(key, _)| key.trim().eq_ignore_ascii_case("for"))
+                    .map(|(_, value)| parse_node(value))
+            })
+            .collect()
+    } else {
+        header_values("x-real-ip").iter().map(|v| parse_node(v)).collect()
+    };
+
+    let mut client = peer;
+    for hop in chain.into_iter().rev() {
+        let Some(ip) = hop else {
+            return Some(peer);
+        };
+        client = ip;
+        if !is_trusted(&ip) {
+            break;
+        }
+    }
+    Some(client)
+}
+
+/// 解析转发头中的单个地址，支持带引号、IPv6方括号和端口的写法
+fn parse_node(value: &str) -> Option<IpAddr> {
+    let value = value.trim().trim_matches('"');
+    if let Some(rest) = value.strip_prefix('[') {
+        return rest.split_once(']')?.0.parse().ok();
+    }
+    value
+        .parse()
+        .ok()
+        .or_else(|| value.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+}
+
+/// 校验 `Authorization: Bearer <key>`，没有配置任何API key时直接放行
+pub async fn api_key_auth<B>(
+    mut req: Request<B>,
+    next: Next<B>,
+    config: Arc<AppConfig>,
+) -> Result<Response, ProxyError> {
+    if config.api_keys.is_empty() {
+        return Ok(next.run(req).await);
+    }
+
+    let key = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(str::trim)
+        .filter(|key| !key.is_empty())
+        .ok_or_else(|| {
+            ProxyError::Unauthorized(
+                "You didn't provide an API key. Provide it in the Authorization header as 'Bearer YOUR_KEY'.".to_string(),
+            )
+        })?;
+
+    let key_sha256 = hash_api_key(key);
+    let Some(api_key) = config.api_keys.iter().find(|k| k.key_sha256 == key_sha256) else {
+        tracing::warn!("Rejected request with unknown API key");
+        return Err(ProxyError::Unauthorized("Incorrect API key provided.".to_string()));
+    };
+
+    req.extensions_mut().insert(ClientIdentity::from_api_key(api_key, &config));
+    Ok(next.run(req).await)
+}
+
+/// API key的SHA-256（小写十六进制），配置文件中只保存这个值
+pub fn hash_api_key(key: &str) -> String {
+    hex::encode(Sha256::digest(key.as_bytes()))
+}
+
+// 使用异步中间件处理函数的方式定义中间件，需在 `api_key_auth` 之后运行
+// 无论请求是否被拒绝，响应都会带上 `x-ratelimit-*` 响应头
 pub async fn rate_limiter<B>(
-    ConnectInfo(addr): ConnectInfo<std::net::SocketAddr>,
-    req: Request<B>,
+    mut req: Request<B>,
     next: Next<B>,
     tracker: Arc<Mutex<RequestTracker>>,
     config: Arc<AppConfig>,
-) -> Result<Response, StatusCode> {
-    let ip = addr.ip();
+    metrics: SharedMetrics,
+) -> Response {
+    // 认证通过的请求按API key统计，否则按客户端IP统计
+    let client = match req.extensions().get::<ClientIdentity>() {
+        Some(client) => client.clone(),
+        None => {
+            let ip = req
+                .extensions()
+                .get::<ClientIp>()
+                .map_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED), |ClientIp(ip)| *ip);
+            let client = ClientIdentity::from_ip(ip, &config);
+            req.extensions_mut().insert(client.clone());
+            client
+        }
+    };
     let now = Instant::now();
-    
+
     // 锁定追踪器并检查请求频率
-    let is_allowed = {
+    let result = {
         let mut tracker = tracker.lock().await;
-        tracker.check_and_record_request(ip, &config, now)
+        tracker.check_and_record_request(&client, now)
     };
-    
-    if !is_allowed {
-        tracing::warn!("Rate limit exceeded for IP: {}", ip);
-        return Err(StatusCode::TOO_MANY_REQUESTS);
-    }
-    
+
     // 继续处理请求
-    Ok(next.run(req).await)
+    let mut response = match result {
+        Ok(()) => next.run(req).await,
+        Err(e) => {
+            tracing::warn!("Rate limit exceeded for {}: {}", client.id, e);
+            let reason = if matches!(e, ProxyError::QuotaExceeded(_)) { "quota" } else { "requests" };
+            metrics.record_rate_limit_rejection(reason);
+            e.into_response()
+        }
+    };
+
+    // 处理完成后再读取状态，包含本次请求预留和结算的token
+    let status = tracker.lock().await.rate_limit_status(&client, Instant::now());
+    status.apply(response.headers_mut());
+    response
 }
 
-// 记录token用量的函数
-pub async fn record_token_usage(
-    ip: IpAddr, 
+// 预留本次请求可能用到的token（估算的提示词 + max_tokens）
+pub async fn reserve_tokens(
+    client: &ClientIdentity,
     tokens: u32,
-    tracker: SharedRequestTracker,
-    config: Arc<AppConfig>,
-) -> bool {
+    tracker: &SharedRequestTracker,
+) -> Result<TokenReservation, ProxyError> {
+    let now = Instant::now();
+    let mut tracker = tracker.lock().await;
+    tracker.reserve_tokens(client, tokens, now).map_err(|e| {
+        tracing::warn!("Token rate limit exceeded for {}: {}", client.id, e);
+        e
+    })
+}
+
+// 按实际用量结算预留的token，上游失败时实际用量为0
+pub async fn settle_tokens(
+    client: &ClientIdentity,
+    reservation: TokenReservation,
+    used: u32,
+    tracker: &SharedRequestTracker,
+) {
     let now = Instant::now();
     let mut tracker = tracker.lock().await;
-    tracker.record_token_usage(ip, tokens, &config, now)
-}
\ No newline at end of file
+    tracker.settle_tokens(client, reservation, used, now)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gcra_allows_a_full_minute_burst_then_rejects() {
+        let now = Instant::now();
+        let mut gcra = Gcra::new(now);
+        for _ in 0..60 {
+            assert!(gcra.try_acquire(now, 1, 60).is_ok());
+        }
+        let retry_after = gcra.try_acquire(now, 1, 60).unwrap_err();
+        assert_eq!(retry_after, Duration::from_secs(1));
+        assert_eq!(gcra.remaining(now, 60), 0);
+    }
+
+    #[test]
+    fn gcra_refills_gradually_instead_of_at_window_edges() {
+        let now = Instant::now();
+        let mut gcra = Gcra::new(now);
+        assert!(gcra.try_acquire(now, 60, 60).is_ok());
+
+        // 10秒后只恢复了10个单位
+        let later = now + Duration::from_secs(10);
+        assert_eq!(gcra.remaining(later, 60), 10);
+        assert!(gcra.try_acquire(later, 11, 60).is_err());
+        assert!(gcra.try_acquire(later, 10, 60).is_ok());
+    }
+
+    #[test]
+    fn refund_and_charge_adjust_reserved_units() {
+        let now = Instant::now();
+        let mut gcra = Gcra::new(now);
+        assert!(gcra.try_acquire(now, 1000, 1000).is_ok());
+        gcra.refund(now, 900, 1000);
+        assert_eq!(gcra.remaining(now, 1000), 900);
+        gcra.charge(now, 50, 1000);
+        assert_eq!(gcra.remaining(now, 1000), 850);
+    }
+
+    #[test]
+    fn reset_durations_use_openai_format() {
+        assert_eq!(format_reset(Duration::from_millis(20)), "20ms");
+        assert_eq!(format_reset(Duration::from_millis(1500)), "1.5s");
+        assert_eq!(format_reset(Duration::from_secs(1)), "1s");
+        assert_eq!(format_reset(Duration::from_secs(360)), "6m0s");
+    }
+
+    fn forwarded_headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(*name, HeaderValue::from_str(value).unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn forwarded_headers_are_ignored_from_untrusted_peers() {
+        let trusted: Vec<IpNet> = vec!["10.0.0.0/8".parse().unwrap()];
+        let headers = forwarded_headers(&[("x-forwarded-for", "1.2.3.4")]);
+        let peer = IpAddr::from([203, 0, 113, 9]);
+        assert_eq!(client_ip(Some(peer), &headers, &trusted), Some(peer));
+        assert_eq!(client_ip(None, &headers, &trusted), None);
+    }
+
+    #[test]
+    fn client_ip_skips_trusted_hops_from_the_right() {
+        let trusted: Vec<IpNet> = vec!["10.0.0.0/8".parse().unwrap()];
+        let peer = Some(IpAddr::from([10, 0, 0, 2]));
+
+        // 最左边的地址可以被客户端伪造，只取最右边的不可信地址
+        let headers = forwarded_headers(&[("x-forwarded-for", "6.6.6.6, 198.51.100.7, 10.0.0.5")]);
+        assert_eq!(client_ip(peer, &headers, &trusted), Some(IpAddr::from([198, 51, 100, 7])));
+
+        let headers = forwarded_headers(&[("forwarded", r#"for="[2001:db8::17]:4711";proto=https, for=10.0.0.5"#)]);
+        assert_eq!(client_ip(peer, &headers, &trusted), "2001:db8::17".parse().ok());
+
+        let headers = forwarded_headers(&[("x-real-ip", "198.51.100.7")]);
+        assert_eq!(client_ip(peer, &headers, &trusted), Some(IpAddr::from([198, 51, 100, 7])));
+
+        // 无法解析的地址之后的内容不可信，使用对端地址
+        let headers = forwarded_headers(&[("x-forwarded-for", "198.51.100.7, unknown")]);
+        assert_eq!(client_ip(peer, &headers, &trusted), peer);
+    }
+
+    #[test]
+    fn idle_clients_are_evicted() {
+        let now = Instant::now();
+        let mut tracker = RequestTracker::new();
+        let client = ClientIdentity {
+            id: ClientId::Ip(IpAddr::from([127, 0, 0, 1])),
+            limits: ClientLimits {
+                requests_per_minute: 60,
+                tokens_per_minute: 1000,
+                daily_token_quota: None,
+                monthly_token_quota: None,
+            },
+        };
+        assert!(tracker.check_and_record_request(&client, now).is_ok());
+
+        assert_eq!(tracker.evict_idle(now), 0);
+        assert_eq!(tracker.evict_idle(now + Duration::from_secs(1)), 1);
+        assert!(tracker.clients.is_empty());
+    }
+
+    #[test]
+    fn reservations_above_the_limit_are_clamped_and_settled() {
+        let now = Instant::now();
+        let mut tracker = RequestTracker::new();
+        let client = ClientIdentity {
+            id: ClientId::ApiKey("small".to_string()),
+            limits: ClientLimits {
+                requests_per_minute: 60,
+                tokens_per_minute: 500,
+                daily_token_quota: None,
+                monthly_token_quota: None,
+            },
+        };
+
+        // 预留超过限额时不会永远返回429
+        let reservation = tracker.reserve_tokens(&client, 1124, now).unwrap();
+        assert_eq!(reservation.tokens, 500);
+        assert!(tracker.reserve_tokens(&client, 1, now).is_err());
+
+        // 结算时扣除实际用量，超出限额的部分需要等待恢复
+        tracker.settle_tokens(&client, reservation, 800, now);
+        assert!(tracker.reserve_tokens(&client, 1, now + Duration::from_secs(30)).is_err());
+        assert!(tracker.reserve_tokens(&client, 1, now + Duration::from_secs(37)).is_ok());
+    }
+
+    /// 经过 `api_key_auth` 的请求，返回状态码和写入请求扩展的客户端身份
+    async fn authenticate(config: AppConfig, authorization: Option<&str>) -> (u16, serde_json::Value) {
+        use axum::{body::Body, routing::get, Extension, Json, Router};
+        use tower::ServiceExt;
+
+        let config = Arc::new(config);
+        let app = Router::new()
+            .route(
+                "/v1/models",
+                get(|client: Option<Extension<ClientIdentity>>| async move {
+                    Json(client.map(|Extension(client)| (client.id.to_string(), client.limits)))
+                }),
+            )
+            .route_layer(axum::middleware::from_fn(move |req: Request<Body>, next| {
+                api_key_auth(req, next, config.clone())
+            }));
+
+        let mut request = Request::builder().uri("/v1/models");
+        if let Some(authorization) = authorization {
+            request = request.header(AUTHORIZATION, authorization);
+        }
+        let response = app.oneshot(request.body(Body::empty()).unwrap()).await.unwrap();
+        let status = response.status().as_u16();
+        let body = body_bytes(response).await;
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    async fn body_bytes(response: Response) -> Vec<u8> {
+        use axum::body::HttpBody;
+
+        let mut body = response.into_body();
+        let mut bytes = Vec::new();
+        while let Some(chunk) = body.data().await {
+            bytes.extend_from_slice(&chunk.unwrap());
+        }
+        bytes
+    }
+
+    fn config_with_keys() -> AppConfig {
+        let mut config = AppConfig::for_tests(&[("MAX_REQUESTS_PER_MINUTE", "30")]);
+        for (name, key, requests_per_minute) in [("alice", "sk-alice", Some(5)), ("bob", "sk-bob", None)] {
+            config.api_keys.push(ApiKeyConfig {
+                name: name.to_string(),
+                key_sha256: hash_api_key(key),
+                requests_per_minute,
+                tokens_per_minute: requests_per_minute.map(|rpm| rpm * 100),
+                daily_token_quota: Some(1000),
+                monthly_token_quota: None,
+            });
+        }
+        config
+    }
+
+    #[tokio::test]
+    async fn requests_without_a_valid_key_are_rejected() {
+        for authorization in [None, Some("Bearer "), Some("sk-alice"), Some("Bearer sk-mallory")] {
+            let (status, body) = authenticate(config_with_keys(), authorization).await;
+            assert_eq!(status, 401, "{:?}", authorization);
+            assert_eq!(body["error"]["code"], "invalid_api_key");
+        }
+    }
+
+    #[tokio::test]
+    async fn valid_keys_carry_their_own_limits() {
+        let (status, body) = authenticate(config_with_keys(), Some("Bearer sk-alice")).await;
+        assert_eq!(status, 200);
+        assert_eq!(body[0], "key alice");
+        assert_eq!(body[1]["requests_per_minute"], 5);
+        assert_eq!(body[1]["tokens_per_minute"], 500);
+        assert_eq!(body[1]["daily_token_quota"], 1000);
+
+        // 未单独设置的限额使用全局设置
+        let (status, body) = authenticate(config_with_keys(), Some("Bearer sk-bob")).await;
+        assert_eq!(status, 200);
+        assert_eq!(body[0], "key bob");
+        assert_eq!(body[1]["requests_per_minute"], 30);
+        assert_eq!(body[1]["tokens_per_minute"], AppConfig::for_tests(&[]).max_tokens_per_minute);
+    }
+
+    #[tokio::test]
+    async fn requests_pass_through_without_configured_keys() {
+        let (status, body) = authenticate(AppConfig::for_tests(&[]), None).await;
+        assert_eq!(status, 200);
+        // 未认证的请求由限流中间件按IP统计
+        assert!(body.is_null());
+    }
+}
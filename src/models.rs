@@ -0,0 +1,226 @@
+use serde::Serialize;
+use std::collections::HashMap;
+
+use crate::config::{AppConfig, ModelConfig};
+use crate::error::{ProxyError, ProxyResult};
+
+/// 未配置 MODELS_FILE 时使用的内置模型列表：(id, 上游slug, 别名, 上下文长度)
+const BUILTIN_MODELS: &[(&str, &str, &[&str], u32)] = &[
+    // 旧模型映射
+    ("gpt-3.5-turbo", "text-davinci-002-render-sha", &["gpt-3.5-turbo-0613", "gpt-3.5-turbo-16k", "gpt-3.5-turbo-16k-0613"], 16_385),
+    ("gpt-4", "gpt-4", &["gpt-4-0613"], 8_192),
+    ("gpt-4-32k", "gpt-4-32k", &["gpt-4-32k-0613"], 32_768),
+    // 新增模型映射
+    ("gpt-4o", "gpt-4o", &[], 128_000),           // 适用于大多数问题
+    ("gpt-4o-mini", "gpt-4o-mini", &[], 128_000), // 更快地回答大多数问题
+    ("gpt-4.5-preview", "gpt-4.5-preview", &["gpt-4.5"], 128_000), // 研究预览版，擅长写作和构思想法
+    ("o1", "o1", &[], 200_000),                   // 使用高级推理
+    ("o1-pro", "o1-pro", &[], 200_000),           // 擅长模糊逻辑推理
+    ("o3-mini", "o3-mini", &[], 200_000),         // 快速进行高级推理
+    ("o3-mini-high", "o3-mini-high", &[], 200_000), // 擅长编码和逻辑
+    ("gpt-4-turbo", "gpt-4-turbo", &[], 128_000), // 传统模型推理
+];
+
+/// 无法解析的模型在指标中的标签
+const UNKNOWN_MODEL: &str = "unknown";
+
+/// 请求中的模型名解析后的结果
+#[derive(Debug, Clone)]
+pub struct ResolvedModel {
+    /// 发给上游的模型名
+    pub upstream: String,
+    /// 指定的后端，None表示使用默认后端
+    pub backend: Option<String>,
+    /// 上下文长度，透传的未知模型为None
+    pub context_window: Option<u32>,
+}
+
+/// `/v1/models` 中的模型对象
+#[derive(Debug, Clone, Serialize)]
+pub struct ModelObject {
+    pub id: String,
+    pub object: &'static str,
+    pub created: i64,
+    pub owned_by: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub context_window: Option<u32>,
+}
+
+/// 模型注册表：决定请求可以使用哪些模型、映射到哪个上游模型和后端，并提供 `/v1/models` 的内容
+pub struct ModelRegistry {
+    models: Vec<ModelConfig>,
+    // 模型id和别名 -> models中的下标
+    index: HashMap<String, usize>,
+    passthrough: bool,
+}
+
+impl ModelRegistry {
+    /// 使用 MODELS_FILE 中的模型（未配置时使用内置列表），并合并 MODEL_BACKENDS 中的模型
+    pub fn from_config(config: &AppConfig) -> anyhow::Result<Self> {
+        let models = if config.models.is_empty() {
+            builtin_models()
+        } else {
+            config.models.clone()
+        };
+        Self::new(models, &config.model_backends, config.model_passthrough)
+    }
+
+    fn new(
+        models: Vec<ModelConfig>,
+        model_backends: &HashMap<String, String>,
+        passthrough: bool,
+    ) -> anyhow::Result<Self> {
+        let mut registry = Self {
+            models: Vec::new(),
+            index: HashMap::new(),
+            passthrough,
+        };
+        for model in models {
+            registry.insert(model)?;
+        }
+
+        // MODEL_BACKENDS 可以覆盖已有模型的后端，也可以直接注册新模型
+        for (name, backend) in model_backends {
+            match registry.index.get(name) {
+                Some(&i) => registry.models[i].backend = Some(backend.clone()),
+                None => registry.insert(ModelConfig {
+                    id: name.clone(),
+                    upstream: None,
+                    aliases: Vec::new(),
+                    context_window: None,
+                    owned_by: backend.clone(),
+                    backend: Some(backend.clone()),
+                })?,
+            }
+        }
+
+        Ok(registry)
+    }
+
+    fn insert(&mut self, model: ModelConfig) -> anyhow::Result<()> {
+        let position = self.models.len();
+        for name in std::iter::once(&model.id).chain(&model.aliases) {
+            if self.index.insert(name.clone(), position).is_some() {
+                anyhow::bail!("Model name '{}' is defined more than once", name);
+            }
+        }
+        self.models.push(model);
+        Ok(())
+    }
+
+    /// 解析请求中的模型名，未知模型在未开启透传时返回404
+    pub fn resolve(&self, name: &str) -> ProxyResult<ResolvedModel> {
+        match self.index.get(name) {
+            Some(&i) => {
+                let model = &self.models[i];
+                Ok(ResolvedModel {
+                    upstream: model.upstream.clone().unwrap_or_else(|| model.id.clone()),
+                    backend: model.backend.clone(),
+                    context_window: model.context_window,
+                })
+            }
+            None if self.passthrough => Ok(ResolvedModel {
+                upstream: name.to_string(),
+                backend: None,
+                context_window: None,
+            }),
+            None => Err(ProxyError::ModelNotFound(format!(
+                "The model '{}' does not exist or you do not have access to it.",
+                name
+            ))),
+        }
+    }
+
+    /// 指标中使用的模型标签：注册表中的模型id，别名归到同一个id，
+    /// 未注册的模型（包括透传的模型）统一为 `unknown`，避免客户端制造任意多的时间序列
+    pub fn metric_label(&self, name: &str) -> &str {
+        self.index.get(name).map_or(UNKNOWN_MODEL, |&i| self.models[i].id.as_str())
+    }
+
+    /// 模型引用的全部后端名称，用于启动时检查
+    pub fn referenced_backends(&self) -> impl Iterator<Item = &str> {
+        self.models.iter().filter_map(|m| m.backend.as_deref())
+    }
+
+    pub fn list(&self) -> Vec<ModelObject> {
+        self.models.iter().map(to_object).collect()
+    }
+
+    /// 按id或别名查找模型
+    pub fn get(&self, name: &str) -> Option<ModelObject> {
+        self.index.get(name).map(|&i| to_object(&self.models[i]))
+    }
+}
+
+fn to_object(model: &ModelConfig) -> ModelObject {
+    ModelObject {
+        id: model.id.clone(),
+        object: "model",
+        created: 0,
+        owned_by: model.owned_by.clone(),
+        context_window: model.context_window,
+    }
+}
+
+fn builtin_models() -> Vec<ModelConfig> {
+    BUILTIN_MODELS
+        .iter()
+        .map(|(id, upstream, aliases, context_window)| ModelConfig {
+            id: id.to_string(),
+            upstream: Some(upstream.to_string()),
+            aliases: aliases.iter().map(|a| a.to_string()).collect(),
+            context_window: Some(*context_window),
+            owned_by: "openai".to_string(),
+            backend: None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn aliases_resolve_to_upstream_slug() {
+        let registry = ModelRegistry::new(builtin_models(), &HashMap::new(), false).unwrap();
+
+        assert_eq!(registry.resolve("gpt-3.5-turbo-16k").unwrap().upstream, "text-davinci-002-render-sha");
+        assert_eq!(registry.resolve("gpt-4.5").unwrap().upstream, "gpt-4.5-preview");
+        assert_eq!(registry.get("gpt-4.5").unwrap().id, "gpt-4.5-preview");
+        assert!(matches!(registry.resolve("gpt-5"), Err(ProxyError::ModelNotFound(_))));
+    }
+
+    #[test]
+    fn passthrough_forwards_unknown_models() {
+        let registry = ModelRegistry::new(builtin_models(), &HashMap::new(), true).unwrap();
+
+        let resolved = registry.resolve("gpt-5").unwrap();
+        assert_eq!(resolved.upstream, "gpt-5");
+        assert!(resolved.backend.is_none());
+        assert!(registry.get("gpt-5").is_none());
+        assert_eq!(registry.metric_label("gpt-5"), "unknown");
+        assert_eq!(registry.metric_label("gpt-4.5"), "gpt-4.5-preview");
+    }
+
+    #[test]
+    fn model_backends_override_and_register() {
+        let model_backends = HashMap::from([
+            ("gpt-4o".to_string(), "openai".to_string()),
+            ("llama-3".to_string(), "vllm".to_string()),
+        ]);
+        let registry = ModelRegistry::new(builtin_models(), &model_backends, false).unwrap();
+
+        let gpt4o = registry.resolve("gpt-4o").unwrap();
+        assert_eq!((gpt4o.upstream.as_str(), gpt4o.backend.as_deref()), ("gpt-4o", Some("openai")));
+        let llama = registry.resolve("llama-3").unwrap();
+        assert_eq!((llama.upstream.as_str(), llama.backend.as_deref()), ("llama-3", Some("vllm")));
+        assert_eq!(registry.list().len(), BUILTIN_MODELS.len() + 1);
+    }
+
+    #[test]
+    fn duplicate_names_are_rejected() {
+        let mut models = builtin_models();
+        models[1].aliases.push("gpt-4o".to_string());
+        assert!(ModelRegistry::new(models, &HashMap::new(), false).is_err());
+    }
+}
@@ -1,31 +1,242 @@
 use serde::{Deserialize, Serialize};
 
+use crate::attachments::UploadedImages;
+use crate::conversation_store::TurnRecorder;
+use crate::error::{ProxyError, ProxyResult};
+
 /// ChatGPT请求体 - 与官方OpenAI API兼容
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize)]
 pub struct ChatCompletionRequest {
     pub model: String,
     pub messages: Vec<Message>,
     #[serde(default)]
-    #[allow(dead_code)]
     pub max_tokens: Option<u32>,
     #[serde(default)]
     pub temperature: Option<f64>,
     #[serde(default)]
     pub top_p: Option<f64>,
-    #[allow(dead_code)]
+    #[serde(default)]
     pub frequency_penalty: Option<f64>,
     #[serde(default)]
-    #[allow(dead_code)]
     pub presence_penalty: Option<f64>,
+    /// 是否以SSE流式返回
+    #[serde(default)]
+    pub stream: bool,
+    /// 终端用户标识
+    #[serde(default)]
+    pub user: Option<String>,
+    /// 由代理截断输出的停止序列
+    #[serde(default)]
+    pub stop: Option<StopSequences>,
+    /// 生成的回复数量，大于1时并行请求上游
+    #[serde(default)]
+    pub n: Option<u32>,
+    /// 可调用的工具，由代理在提示词中模拟
+    #[serde(default)]
+    pub tools: Option<Vec<Tool>>,
+    #[serde(default)]
+    pub tool_choice: Option<ToolChoice>,
+    /// 要求的输出格式，JSON格式由代理提示并校验
+    #[serde(default)]
+    pub response_format: Option<ResponseFormat>,
+    /// 客户端会话id（来自 X-Conversation-Id 请求头），用于续接上游会话
+    #[serde(skip)]
+    pub conversation_key: Option<String>,
+    /// 模型注册表解析出的上游模型名
+    #[serde(skip)]
+    pub upstream_model: Option<String>,
+    /// 本次请求已上传到网页端的图片，clone出的请求共用
+    #[serde(skip)]
+    pub uploaded_images: UploadedImages,
+    /// 上游回复后等待按返回给客户端的内容保存的会话
+    #[serde(skip)]
+    pub conversation_turn: TurnRecorder,
     // 可根据需要扩展更多字段
 }
 
-/// 用户 / 系统 / 助手消息
-#[derive(Debug, Deserialize)]
+impl ChatCompletionRequest {
+    /// 请求中是否包含图片
+    pub fn has_images(&self) -> bool {
+        self.messages.iter().any(|m| m.content.images().next().is_some())
+    }
+
+    /// 请求中是否有需要下载的图片地址（不是 `data:` URL）
+    pub fn has_image_links(&self) -> bool {
+        self.messages
+            .iter()
+            .flat_map(|m| m.content.images())
+            .any(|image| !image.url.starts_with("data:"))
+    }
+
+    /// 发给上游的模型名，未经过模型注册表解析时使用请求中的原名
+    pub fn upstream_model(&self) -> &str {
+        self.upstream_model.as_deref().unwrap_or(&self.model)
+    }
+}
+
+/// 用户 / 系统 / 助手 / 工具消息
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Message {
-    #[allow(dead_code)]
-    pub role: String,   // "user", "assistant", "system"
-    pub content: String,
+    pub role: String,   // "user", "assistant", "system", "tool"
+    /// 只包含工具调用的assistant消息可以没有内容
+    #[serde(default, deserialize_with = "null_as_empty")]
+    pub content: MessageContent,
+    /// assistant消息发起的工具调用
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_calls: Option<Vec<ToolCall>>,
+    /// tool消息对应的调用id
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_call_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+}
+
+impl Message {
+    /// 只有文本内容的消息
+    pub fn text(role: &str, content: String) -> Self {
+        Self {
+            role: role.to_string(),
+            content: content.into(),
+            tool_calls: None,
+            tool_call_id: None,
+            name: None,
+        }
+    }
+}
+
+fn null_as_empty<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<MessageContent, D::Error> {
+    Ok(Option::<MessageContent>::deserialize(deserializer)?.unwrap_or_default())
+}
+
+/// 工具定义，目前只有 `function` 一种
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Tool {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub function: FunctionDefinition,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct FunctionDefinition {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// 参数的JSON Schema
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parameters: Option<serde_json::Value>,
+}
+
+/// `tool_choice`：`"none"` / `"auto"` / `"required"`，或指定某个函数
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum ToolChoice {
+    Mode(String),
+    Function { function: ToolChoiceFunction },
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ToolChoiceFunction {
+    pub name: String,
+}
+
+/// `response_format`：普通文本、任意JSON对象，或符合给定JSON Schema的JSON
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ResponseFormat {
+    Text,
+    JsonObject,
+    JsonSchema { json_schema: JsonSchemaFormat },
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct JsonSchemaFormat {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub schema: Option<serde_json::Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub strict: Option<bool>,
+}
+
+/// 一次工具调用
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct ToolCall {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub function: FunctionCall,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct FunctionCall {
+    pub name: String,
+    /// JSON编码的参数
+    pub arguments: String,
+}
+
+/// 消息内容，可以是字符串或由文本和图片组成的数组
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum MessageContent {
+    Text(String),
+    Parts(Vec<ContentPart>),
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ContentPart {
+    Text { text: String },
+    ImageUrl { image_url: ImageUrl },
+}
+
+/// 图片地址，支持 `data:` URL 和 http(s) URL
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ImageUrl {
+    pub url: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+impl MessageContent {
+    /// 全部文本，多个文本部分之间以换行连接
+    pub fn text(&self) -> String {
+        match self {
+            Self::Text(text) => text.clone(),
+            Self::Parts(parts) => parts
+                .iter()
+                .filter_map(|part| match part {
+                    ContentPart::Text { text } => Some(text.as_str()),
+                    ContentPart::ImageUrl { .. } => None,
+                })
+                .collect::<Vec<_>>()
+                .join("\n"),
+        }
+    }
+
+    /// 内容中的图片
+    pub fn images(&self) -> impl Iterator<Item = &ImageUrl> {
+        let parts = match self {
+            Self::Text(_) => &[][..],
+            Self::Parts(parts) => parts.as_slice(),
+        };
+        parts.iter().filter_map(|part| match part {
+            ContentPart::ImageUrl { image_url } => Some(image_url),
+            ContentPart::Text { .. } => None,
+        })
+    }
+}
+
+impl Default for MessageContent {
+    fn default() -> Self {
+        Self::Text(String::new())
+    }
+}
+
+impl From<String> for MessageContent {
+    fn from(text: String) -> Self {
+        Self::Text(text)
+    }
 }
 
 /// ChatGPT响应体 - 与官方OpenAI API兼容
@@ -49,7 +260,10 @@ pub struct Choice {
 #[derive(Debug, Serialize)]
 pub struct MessageResponse {
     pub role: String,
-    pub content: String,
+    /// 只有工具调用时为null
+    pub content: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_calls: Option<Vec<ToolCall>>,
 }
 
 #[derive(Debug, Serialize)]
@@ -58,3 +272,229 @@ pub struct Usage {
     pub completion_tokens: i64,
     pub total_tokens: i64,
 }
+
+/// 流式响应块 - 与官方OpenAI API的 chat.completion.chunk 兼容
+#[derive(Debug, Serialize)]
+pub struct ChatCompletionChunk {
+    pub id: String,
+    #[serde(rename = "object")]
+    pub object: String,
+    pub created: i64,
+    pub model: String,
+    pub choices: Vec<ChunkChoice>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ChunkChoice {
+    pub index: usize,
+    pub delta: Delta,
+    pub finish_reason: Option<String>,
+}
+
+/// 流式增量内容，首块携带role，之后只携带content或tool_calls
+#[derive(Debug, Default, Serialize)]
+pub struct Delta {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub role: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_calls: Option<Vec<ToolCallDelta>>,
+}
+
+/// 流式响应中的工具调用，每个调用在一个增量中完整发送
+#[derive(Debug, Serialize)]
+pub struct ToolCallDelta {
+    pub index: usize,
+    #[serde(flatten)]
+    pub call: ToolCall,
+}
+
+/// `stop` 参数，可以是单个字符串或最多4个字符串的数组
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum StopSequences {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl StopSequences {
+    /// 检查数量并去掉空字符串
+    pub fn to_vec(&self) -> ProxyResult<Vec<String>> {
+        let stops: Vec<String> = match self {
+            Self::One(stop) => vec![stop.clone()],
+            Self::Many(stops) => stops.clone(),
+        };
+        if stops.len() > 4 {
+            return Err(ProxyError::BadRequest("'stop' accepts at most 4 sequences".to_string()));
+        }
+        Ok(stops.into_iter().filter(|s| !s.is_empty()).collect())
+    }
+}
+
+/// 旧版文本补全请求体 - 与官方OpenAI API的 /v1/completions 兼容
+#[derive(Debug, Deserialize)]
+pub struct CompletionRequest {
+    pub model: String,
+    pub prompt: CompletionPrompt,
+    /// 补全内容之后的文本
+    #[serde(default)]
+    pub suffix: Option<String>,
+    /// 是否在返回的文本前加上提示词
+    #[serde(default)]
+    pub echo: bool,
+    #[serde(default)]
+    pub stop: Option<StopSequences>,
+    #[serde(default)]
+    pub max_tokens: Option<u32>,
+    #[serde(default)]
+    pub temperature: Option<f64>,
+    #[serde(default)]
+    pub top_p: Option<f64>,
+    #[serde(default)]
+    pub frequency_penalty: Option<f64>,
+    #[serde(default)]
+    pub presence_penalty: Option<f64>,
+    #[serde(default)]
+    pub n: Option<u32>,
+    #[serde(default)]
+    pub stream: bool,
+    #[serde(default)]
+    pub user: Option<String>,
+}
+
+/// 提示词，可以是字符串或只含一个字符串的数组
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum CompletionPrompt {
+    Text(String),
+    Batch(Vec<String>),
+}
+
+/// 让聊天模型只输出续写内容
+const COMPLETION_INSTRUCTION: &str =
+    "Continue the text in the user message. Reply with the continuation only, without repeating the given text or adding any commentary.";
+
+impl CompletionRequest {
+    /// 提示词文本，上游一次只能生成一个回复，不支持多个提示词
+    pub fn prompt_text(&self) -> ProxyResult<&str> {
+        match &self.prompt {
+            CompletionPrompt::Text(text) => Ok(text),
+            CompletionPrompt::Batch(prompts) => match prompts.as_slice() {
+                [text] => Ok(text),
+                _ => Err(ProxyError::BadRequest("'prompt' must be a string or an array with one string".to_string())),
+            },
+        }
+    }
+
+    /// 转换为单轮对话：system消息要求续写，user消息为提示词
+    pub fn to_chat_request(&self) -> ProxyResult<ChatCompletionRequest> {
+        let mut instruction = COMPLETION_INSTRUCTION.to_string();
+        if let Some(suffix) = self.suffix.as_deref().filter(|s| !s.is_empty()) {
+            instruction.push_str(&format!(
+                " The continuation will be followed by the text below, so it must lead naturally into it without repeating it:\n{}",
+                suffix
+            ));
+        }
+
+        Ok(ChatCompletionRequest {
+            model: self.model.clone(),
+            messages: vec![
+                Message::text("system", instruction),
+                Message::text("user", self.prompt_text()?.to_string()),
+            ],
+            max_tokens: self.max_tokens,
+            temperature: self.temperature,
+            top_p: self.top_p,
+            frequency_penalty: self.frequency_penalty,
+            presence_penalty: self.presence_penalty,
+            stream: self.stream,
+            user: self.user.clone(),
+            stop: self.stop.clone(),
+            n: self.n,
+            tools: None,
+            tool_choice: None,
+            response_format: None,
+            conversation_key: None,
+            upstream_model: None,
+            uploaded_images: UploadedImages::default(),
+            conversation_turn: TurnRecorder::default(),
+        })
+    }
+}
+
+/// 文本补全响应体，流式响应的每一块也使用这个结构（不带usage）
+#[derive(Debug, Serialize)]
+pub struct TextCompletionResponse {
+    pub id: String,
+    #[serde(rename = "object")]
+    pub object: String,
+    pub created: i64,
+    pub model: String,
+    pub choices: Vec<TextChoice>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub usage: Option<Usage>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TextChoice {
+    pub text: String,
+    pub index: usize,
+    /// 上游不提供logprobs，始终为null
+    pub logprobs: Option<serde_json::Value>,
+    pub finish_reason: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn completion(body: serde_json::Value) -> CompletionRequest {
+        serde_json::from_value(body).unwrap()
+    }
+
+    #[test]
+    fn prompt_must_be_a_single_text() {
+        let request = completion(serde_json::json!({ "model": "gpt-3.5-turbo", "prompt": ["Once upon"] }));
+        assert_eq!(request.prompt_text().unwrap(), "Once upon");
+
+        for prompt in [serde_json::json!(["one", "two"]), serde_json::json!([])] {
+            let request = completion(serde_json::json!({ "model": "gpt-3.5-turbo", "prompt": prompt }));
+            assert!(matches!(request.prompt_text(), Err(ProxyError::BadRequest(_))));
+            assert!(request.to_chat_request().is_err());
+        }
+    }
+
+    #[test]
+    fn completion_becomes_a_single_turn_chat() {
+        let request = completion(serde_json::json!({
+            "model": "gpt-3.5-turbo",
+            "prompt": "def add(a, b):",
+            "suffix": "\nprint(add(1, 2))",
+            "stop": ["\n\n"],
+            "n": 2,
+            "max_tokens": 64,
+            "stream": true,
+        }));
+        let chat = request.to_chat_request().unwrap();
+
+        assert_eq!(chat.messages.len(), 2);
+        assert_eq!(chat.messages[0].role, "system");
+        let instruction = chat.messages[0].content.text();
+        assert!(instruction.starts_with(COMPLETION_INSTRUCTION));
+        assert!(instruction.ends_with(":\n\nprint(add(1, 2))"), "{}", instruction);
+        assert_eq!(chat.messages[1].role, "user");
+        assert_eq!(chat.messages[1].content.text(), "def add(a, b):");
+
+        assert_eq!(chat.stop.unwrap().to_vec().unwrap(), ["\n\n"]);
+        assert_eq!(chat.n, Some(2));
+        assert_eq!(chat.max_tokens, Some(64));
+        assert!(chat.stream);
+    }
+
+    #[test]
+    fn empty_suffix_keeps_the_plain_instruction() {
+        let request = completion(serde_json::json!({ "model": "gpt-3.5-turbo", "prompt": "Hi", "suffix": "" }));
+        assert_eq!(request.to_chat_request().unwrap().messages[0].content.text(), COMPLETION_INSTRUCTION);
+    }
+}
@@ -0,0 +1,184 @@
+use crate::utils;
+
+/// 在增量文本中查找停止序列
+///
+/// 上游不支持 `stop` 参数，只能由代理截断输出。停止序列可能被拆在两个增量中，
+/// 因此每次都保留末尾可能是停止序列开头的部分，等后续内容到达后再输出。
+pub struct StopScanner {
+    stops: Vec<String>,
+    held: String,
+}
+
+impl StopScanner {
+    pub fn new(stops: Vec<String>) -> Self {
+        Self {
+            stops,
+            held: String::new(),
+        }
+    }
+
+    /// 输入新增文本，返回可以输出的部分；第二项为true表示遇到了停止序列，
+    /// 此时停止序列及之后的内容留在 `finish` 中
+    pub fn push(&mut self, text: &str) -> (String, bool) {
+        self.held.push_str(text);
+
+        let first_stop = self.stops.iter().filter_map(|stop| self.held.find(stop.as_str())).min();
+        if let Some(position) = first_stop {
+            let rest = self.held.split_off(position);
+            return (std::mem::replace(&mut self.held, rest), true);
+        }
+
+        // 保留最长的、同时是某个停止序列开头的后缀
+        let split = (0..self.held.len())
+            .filter(|&i| self.held.is_char_boundary(i))
+            .find(|&i| self.stops.iter().any(|stop| stop.starts_with(&self.held[i..])))
+            .unwrap_or(self.held.len());
+        let rest = self.held.split_off(split);
+        (std::mem::replace(&mut self.held, rest), false)
+    }
+
+    /// 返回保留的剩余内容
+    pub fn finish(&mut self) -> String {
+        std::mem::take(&mut self.held)
+    }
+}
+
+/// 回复结束的原因，对应响应中的 `finish_reason`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FinishReason {
+    /// 正常结束或遇到停止序列
+    Stop,
+    /// 达到 `max_tokens`
+    Length,
+    /// 回复是工具调用
+    ToolCalls,
+}
+
+impl FinishReason {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Stop => "stop",
+            Self::Length => "length",
+            Self::ToolCalls => "tool_calls",
+        }
+    }
+}
+
+/// 代理侧执行的 `stop` 和 `max_tokens` 限制
+///
+/// 上游不支持这两个参数，只能截断输出。token数与用量统计使用同样的估算方法。
+pub struct OutputLimiter {
+    stops: StopScanner,
+    max_chars: Option<usize>,
+    emitted_chars: usize,
+}
+
+impl OutputLimiter {
+    pub fn new(stops: Vec<String>, max_tokens: Option<u32>) -> Self {
+        Self {
+            stops: StopScanner::new(stops),
+            max_chars: max_tokens.map(|tokens| tokens as usize * utils::CHARS_PER_TOKEN),
+            emitted_chars: 0,
+        }
+    }
+
+    /// 输入新增文本，返回可以输出的部分；返回结束原因时之后的内容都应丢弃
+    pub fn push(&mut self, text: &str) -> (String, Option<FinishReason>) {
+        let (mut output, stopped) = self.stops.push(text);
+        if self.truncate_to_budget(&mut output) {
+            return (output, Some(FinishReason::Length));
+        }
+        (output, stopped.then_some(FinishReason::Stop))
+    }
+
+    /// 上游输出结束，返回保留的剩余内容和结束原因
+    pub fn finish(&mut self) -> (String, FinishReason) {
+        let mut rest = self.stops.finish();
+        if self.truncate_to_budget(&mut rest) {
+            return (rest, FinishReason::Length);
+        }
+        (rest, FinishReason::Stop)
+    }
+
+    /// 输入最后一段文本并结束，返回可以输出的部分和结束原因
+    pub fn finish_with(&mut self, text: &str) -> (String, FinishReason) {
+        let (mut output, finish_reason) = self.push(text);
+        if let Some(finish_reason) = finish_reason {
+            return (output, finish_reason);
+        }
+        let (rest, finish_reason) = self.finish();
+        output.push_str(&rest);
+        (output, finish_reason)
+    }
+
+    /// 超出 `max_tokens` 时截断并返回true
+    fn truncate_to_budget(&mut self, text: &mut String) -> bool {
+        let Some(max_chars) = self.max_chars else {
+            return false;
+        };
+        let remaining = max_chars - self.emitted_chars;
+        match text.char_indices().nth(remaining) {
+            Some((end, _)) => {
+                text.truncate(end);
+                self.emitted_chars = max_chars;
+                true
+            }
+            None => {
+                self.emitted_chars += text.chars().count();
+                false
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stops(items: &[&str]) -> Vec<String> {
+        items.iter().map(|s| s.to_string()).collect()
+    }
+
+    fn apply(text: &str, stops: &[String], max_tokens: Option<u32>) -> (String, FinishReason) {
+        OutputLimiter::new(stops.to_vec(), max_tokens).finish_with(text)
+    }
+
+    #[test]
+    fn stop_split_across_deltas() {
+        let mut scanner = StopScanner::new(stops(&["\n\nUser:"]));
+
+        assert_eq!(scanner.push("Hello"), ("Hello".to_string(), false));
+        // 可能是停止序列开头的部分先保留
+        assert_eq!(scanner.push(" world\n\nUs"), (" world".to_string(), false));
+        assert_eq!(scanner.push("er: next"), (String::new(), true));
+        assert_eq!(scanner.finish(), "\n\nUser: next");
+    }
+
+    #[test]
+    fn held_text_is_flushed_without_stop() {
+        let mut scanner = StopScanner::new(stops(&["END"]));
+
+        assert_eq!(scanner.push("你好E"), ("你好".to_string(), false));
+        assert_eq!(scanner.push("N"), (String::new(), false));
+        assert_eq!(scanner.finish(), "EN");
+    }
+
+    #[test]
+    fn apply_uses_earliest_stop() {
+        assert_eq!(apply("a, b. c", &stops(&[".", ","]), None), ("a".to_string(), FinishReason::Stop));
+        assert_eq!(apply("no stop here", &stops(&["#"]), None), ("no stop here".to_string(), FinishReason::Stop));
+    }
+
+    #[test]
+    fn max_tokens_truncates_across_deltas() {
+        // 2个token按估算为8个字符
+        let mut limiter = OutputLimiter::new(Vec::new(), Some(2));
+
+        assert_eq!(limiter.push("abcde"), ("abcde".to_string(), None));
+        assert_eq!(limiter.push("fgh"), ("fgh".to_string(), None));
+        assert_eq!(limiter.push("ijk"), (String::new(), Some(FinishReason::Length)));
+
+        assert_eq!(apply("12345678", &[], Some(2)), ("12345678".to_string(), FinishReason::Stop));
+        assert_eq!(apply("123456789", &[], Some(2)), ("12345678".to_string(), FinishReason::Length));
+    }
+}
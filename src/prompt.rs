@@ -0,0 +1,241 @@
+use crate::error::{ProxyError, ProxyResult};
+use crate::openai_types::Message;
+
+/// 发给ChatGPT网页端的提示词
+///
+/// 网页端每次只接收一条用户消息，因此把OpenAI格式的消息列表拆成三部分：
+/// 开头的system消息作为带分隔符的前言，之前的对话作为历史记录，
+/// 末尾连续的user消息作为本轮提问。
+#[derive(Debug, Clone, PartialEq)]
+pub struct Prompt {
+    pub system: Option<String>,
+    pub history: Vec<Turn>,
+    pub current: String,
+}
+
+/// 历史记录中的一轮发言
+#[derive(Debug, Clone, PartialEq)]
+pub struct Turn {
+    pub speaker: Speaker,
+    pub content: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Speaker {
+    System,
+    User,
+    Assistant,
+    Tool,
+}
+
+impl Speaker {
+    fn from_role(role: &str) -> ProxyResult<Self> {
+        match role {
+            "system" | "developer" => Ok(Self::System),
+            "user" => Ok(Self::User),
+            "assistant" => Ok(Self::Assistant),
+            "tool" | "function" => Ok(Self::Tool),
+            other => Err(ProxyError::BadRequest(format!("Unsupported message role '{}'", other))),
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            Self::System => "System",
+            Self::User => "User",
+            Self::Assistant => "Assistant",
+            Self::Tool => "Tool result",
+        }
+    }
+}
+
+/// 没有末尾user消息时（例如最后一条是assistant），让上游接着上一条回复继续
+const CONTINUE_INSTRUCTION: &str = "Please continue your last reply from where it stopped.";
+
+impl Prompt {
+    /// 把OpenAI格式的消息列表拆成前言、历史和本轮提问
+    pub fn from_messages(messages: &[Message]) -> ProxyResult<Self> {
+        let mut turns = messages
+            .iter()
+            .map(|m| {
+                Ok(Turn {
+                    speaker: Speaker::from_role(&m.role)?,
+                    content: m.content.text().trim().to_string(),
+                })
+            })
+            .collect::<ProxyResult<Vec<_>>>()?;
+
+        // 开头连续的system消息合并为前言
+        let leading_system = turns.iter().take_while(|t| t.speaker == Speaker::System).count();
+        let system = join_contents(turns.drain(..leading_system));
+
+        // 末尾连续的user消息合并为本轮提问
+        let trailing_user = turns.iter().rev().take_while(|t| t.speaker == Speaker::User).count();
+        let current = join_contents(turns.drain(turns.len() - trailing_user..))
+            .unwrap_or_else(|| CONTINUE_INSTRUCTION.to_string());
+
+        Ok(Self {
+            system,
+            history: turns,
+            current,
+        })
+    }
+
+    /// 渲染为单条消息文本，只有一条user消息时原样返回
+    pub fn render(&self) -> String {
+        let mut sections = Vec::new();
+
+        if let Some(system) = &self.system {
+            sections.push(format!(
+                "[System instructions]\n{}\n[End of system instructions]",
+                system
+            ));
+        }
+
+        if !self.history.is_empty() {
+            let transcript = self
+                .history
+                .iter()
+                .map(|t| format!("{}: {}", t.speaker.label(), t.content))
+                .collect::<Vec<_>>()
+                .join("\n\n");
+            sections.push(format!(
+                "[Conversation history]\n{}\n[End of conversation history]",
+                transcript
+            ));
+        }
+
+        sections.push(self.current.clone());
+        sections.join("\n\n")
+    }
+}
+
+/// 合并多条消息的内容，忽略空内容，全部为空时返回None
+fn join_contents(turns: impl Iterator<Item = Turn>) -> Option<String> {
+    let contents: Vec<String> = turns.map(|t| t.content).filter(|c| !c.is_empty()).collect();
+    if contents.is_empty() {
+        None
+    } else {
+        Some(contents.join("\n\n"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn msg(role: &str, content: &str) -> Message {
+        Message::text(role, content.to_string())
+    }
+
+    #[test]
+    fn single_user_message_is_sent_verbatim() {
+        let prompt = Prompt::from_messages(&[msg("user", "Hello!")]).unwrap();
+        assert_eq!(prompt.render(), "Hello!");
+    }
+
+    #[test]
+    fn system_prompt_becomes_delimited_preamble() {
+        let prompt = Prompt::from_messages(&[
+            msg("system", "You are a helpful assistant."),
+            msg("user", "Hi"),
+        ])
+        .unwrap();
+
+        assert_eq!(prompt.system.as_deref(), Some("You are a helpful assistant."));
+        assert_eq!(
+            prompt.render(),
+            "[System instructions]\nYou are a helpful assistant.\n[End of system instructions]\n\nHi"
+        );
+    }
+
+    #[test]
+    fn prior_turns_are_rendered_as_history() {
+        let prompt = Prompt::from_messages(&[
+            msg("system", "Be brief."),
+            msg("user", "What is 2+2?"),
+            msg("assistant", "4"),
+            msg("user", "And times 3?"),
+        ])
+        .unwrap();
+
+        assert_eq!(
+            prompt.history,
+            vec![
+                Turn { speaker: Speaker::User, content: "What is 2+2?".to_string() },
+                Turn { speaker: Speaker::Assistant, content: "4".to_string() },
+            ]
+        );
+        assert_eq!(prompt.current, "And times 3?");
+        assert_eq!(
+            prompt.render(),
+            "[System instructions]\nBe brief.\n[End of system instructions]\n\n\
+             [Conversation history]\nUser: What is 2+2?\n\nAssistant: 4\n[End of conversation history]\n\n\
+             And times 3?"
+        );
+    }
+
+    #[test]
+    fn consecutive_system_and_user_messages_are_merged() {
+        let prompt = Prompt::from_messages(&[
+            msg("system", "Rule one."),
+            msg("developer", "Rule two."),
+            msg("user", "First part."),
+            msg("user", "Second part."),
+        ])
+        .unwrap();
+
+        assert_eq!(prompt.system.as_deref(), Some("Rule one.\n\nRule two."));
+        assert!(prompt.history.is_empty());
+        assert_eq!(prompt.current, "First part.\n\nSecond part.");
+    }
+
+    #[test]
+    fn later_system_messages_stay_in_history() {
+        let prompt = Prompt::from_messages(&[
+            msg("user", "Hi"),
+            msg("assistant", "Hello"),
+            msg("system", "Now answer in French."),
+            msg("user", "How are you?"),
+        ])
+        .unwrap();
+
+        assert!(prompt.system.is_none());
+        assert_eq!(prompt.history.last().unwrap().speaker, Speaker::System);
+        assert!(prompt.render().contains("System: Now answer in French."));
+    }
+
+    #[test]
+    fn trailing_assistant_message_asks_to_continue() {
+        let prompt = Prompt::from_messages(&[
+            msg("user", "Write a poem"),
+            msg("assistant", "Roses are red,"),
+        ])
+        .unwrap();
+
+        assert_eq!(prompt.history.len(), 2);
+        assert_eq!(prompt.current, CONTINUE_INSTRUCTION);
+    }
+
+    #[test]
+    fn unknown_role_is_rejected() {
+        let err = Prompt::from_messages(&[msg("narrator", "Once upon a time")]).unwrap_err();
+        assert!(matches!(err, ProxyError::BadRequest(_)));
+    }
+
+    #[test]
+    fn content_parts_are_concatenated() {
+        let message: Message = serde_json::from_value(serde_json::json!({
+            "role": "user",
+            "content": [
+                { "type": "text", "text": "What is in" },
+                { "type": "image_url", "image_url": { "url": "https://example.com/cat.png" } },
+                { "type": "text", "text": "this picture?" },
+            ],
+        }))
+        .unwrap();
+
+        let prompt = Prompt::from_messages(&[message]).unwrap();
+        assert_eq!(prompt.render(), "What is in\nthis picture?");
+    }
+}
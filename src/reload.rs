@@ -0,0 +1,300 @@
+use anyhow::Result;
+use axum::{http::Request, middleware::Next, response::Response};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::sync::{watch, Mutex};
+
+use crate::backend::BackendRouter;
+use crate::config::{AppConfig, CliArgs};
+use crate::conversation_store::ConversationStore;
+use crate::credentials::CredentialsStore;
+use crate::http_client::UpstreamClient;
+use crate::models::ModelRegistry;
+use crate::token_cache::AccessTokenCache;
+
+/// 检查配置文件是否变化的间隔
+const WATCH_INTERVAL: Duration = Duration::from_secs(2);
+
+/// 由同一份配置生成的运行状态，热重载时整体替换
+pub struct RuntimeState {
+    pub config: Arc<AppConfig>,
+    pub models: Arc<ModelRegistry>,
+    pub backends: Arc<BackendRouter>,
+}
+
+/// 当前运行状态的只读句柄，可以在中间件之间任意clone
+#[derive(Clone)]
+pub struct SharedState(watch::Receiver<Arc<RuntimeState>>);
+
+impl SharedState {
+    pub fn current(&self) -> Arc<RuntimeState> {
+        self.0.borrow().clone()
+    }
+}
+
+/// 重新加载配置：校验新配置并生成新的模型注册表和后端，全部成功后才替换当前状态
+pub struct ConfigReloader {
+    cli: CliArgs,
+    // 读取配置的函数，测试中替换为不读取进程环境的版本
+    load: fn(&CliArgs) -> Result<AppConfig>,
+    state: watch::Sender<Arc<RuntimeState>>,
+    client: UpstreamClient,
+    credentials: Arc<CredentialsStore>,
+    tokens: Arc<AccessTokenCache>,
+    conversations: Arc<ConversationStore>,
+    // 同一时间只进行一次重载
+    reloading: Mutex<()>,
+}
+
+impl ConfigReloader {
+    /// 用启动时加载的配置创建初始状态
+    pub fn new(
+        cli: CliArgs,
+        config: Arc<AppConfig>,
+        client: UpstreamClient,
+        credentials: Arc<CredentialsStore>,
+        tokens: Arc<AccessTokenCache>,
+        conversations: Arc<ConversationStore>,
+    ) -> Result<(Self, SharedState)> {
+        let models = Arc::new(ModelRegistry::from_config(&config)?);
+        let backends = Arc::new(BackendRouter::from_config(
+            config.clone(),
+            &models,
+            client.clone(),
+            credentials.clone(),
+            tokens.clone(),
+            conversations.clone(),
+        )?);
+        let (state, receiver) = watch::channel(Arc::new(RuntimeState { config, models, backends }));
+
+        let reloader = Self {
+            cli,
+            load: AppConfig::load,
+            state,
+            client,
+            credentials,
+            tokens,
+            conversations,
+            reloading: Mutex::new(()),
+        };
+        let shared = SharedState(receiver);
+        Ok((reloader, shared))
+    }
+
+    /// 重新加载配置，新配置无效时返回错误，当前状态保持不变
+    ///
+    /// 正在处理的请求继续使用各自开始时的状态。
+    pub async fn reload(&self) -> Result<()> {
+        let _guard = self.reloading.lock().await;
+        let config = Arc::new((self.load)(&self.cli)?);
+        let models = Arc::new(ModelRegistry::from_config(&config)?);
+        let backends = Arc::new(BackendRouter::from_config(
+            config.clone(),
+            &models,
+            self.client.clone(),
+            self.credentials.clone(),
+            self.tokens.clone(),
+            self.conversations.clone(),
+        )?);
+
+        let previous = self.state.borrow().config.clone();
+        for setting in restart_required(&previous, &config) {
+            tracing::warn!("{} changed, the new value takes effect after a restart", setting);
+        }
+
+        // 认证信息变化时丢弃缓存的访问令牌
+        if self.credentials.reload(&config).await {
+            tracing::info!("ChatGPT credentials changed, resetting the access token cache");
+            self.tokens.reset().await;
+        }
+
+        tracing::info!(
+            "Configuration reloaded: {} models, {} OpenAI backends, {} API keys",
+            models.list().len(),
+            config.openai_backends.len(),
+            config.api_keys.len()
+        );
+        self.state.send_replace(Arc::new(RuntimeState { config, models, backends }));
+        Ok(())
+    }
+
+    /// 在后台监视配置来源文件的修改时间，并在收到SIGHUP时重新加载
+    pub fn start_watching(self: Arc<Self>) {
+        let watcher = self.clone();
+        tokio::spawn(async move {
+            let mut seen = watcher.fingerprint();
+            let mut interval = tokio::time::interval(WATCH_INTERVAL);
+            loop {
+                interval.tick().await;
+                if watcher.fingerprint() != seen {
+                    tracing::info!("Configuration files changed, reloading");
+                    watcher.reload_and_log().await;
+                    // 重载后监视的文件可能变化（例如新设置了 MODELS_FILE）
+                    seen = watcher.fingerprint();
+                }
+            }
+        });
+
+        #[cfg(unix)]
+        tokio::spawn(async move {
+            use tokio::signal::unix::{signal, SignalKind};
+            let mut hangup = match signal(SignalKind::hangup()) {
+                Ok(hangup) => hangup,
+                Err(e) => {
+                    tracing::error!("Failed to listen for SIGHUP: {}", e);
+                    return;
+                }
+            };
+            while hangup.recv().await.is_some() {
+                tracing::info!("Received SIGHUP, reloading configuration");
+                self.reload_and_log().await;
+            }
+        });
+    }
+
+    async fn reload_and_log(&self) {
+        if let Err(e) = self.reload().await {
+            tracing::error!("Rejected new configuration, keeping the current one: {:#}", e);
+        }
+    }
+
+    /// 配置来源文件的修改时间和大小，文件不存在时为None
+    fn fingerprint(&self) -> Vec<(PathBuf, Option<(SystemTime, u64)>)> {
+        self.state
+            .borrow()
+            .config
+            .source_files
+            .iter()
+            .map(|path| {
+                let metadata = std::fs::metadata(path).ok();
+                let stamp = metadata.and_then(|m| Some((m.modified().ok()?, m.len())));
+                (path.clone(), stamp)
+            })
+            .collect()
+    }
+}
+
+/// 把当前状态中的配置、模型注册表和后端放入请求扩展，供处理函数通过 `Extension` 读取
+pub async fn inject_state<B>(mut req: Request<B>, next: Next<B>, state: SharedState) -> Response {
+    let current = state.current();
+    req.extensions_mut().insert(current.config.clone());
+    req.extensions_mut().insert(current.models.clone());
+    req.extensions_mut().insert(current.backends.clone());
+    next.run(req).await
+}
+
+/// 只在启动时读取、修改后需要重启才能生效的设置
+fn restart_required(old: &AppConfig, new: &AppConfig) -> Vec<&'static str> {
+    let mut changed = Vec::new();
+    let mut check = |name, differs: bool| {
+        if differs {
+            changed.push(name);
+        }
+    };
+    check("SERVER_PORT", old.server_port != new.server_port);
+    check(
+        "UPSTREAM_* client settings",
+        old.upstream_connect_timeout_secs != new.upstream_connect_timeout_secs
+            || old.upstream_request_timeout_secs != new.upstream_request_timeout_secs
+            || old.upstream_pool_max_idle_per_host != new.upstream_pool_max_idle_per_host
+            || old.upstream_pool_idle_timeout_secs != new.upstream_pool_idle_timeout_secs,
+    );
+    check(
+        "Outbound proxy settings",
+        old.http_proxy != new.http_proxy
+            || old.https_proxy != new.https_proxy
+            || old.all_proxy != new.all_proxy
+            || old.no_proxy != new.no_proxy,
+    );
+    check(
+        "CONVERSATION_TTL_SECS / CONVERSATION_MAX_ENTRIES",
+        old.conversation_ttl_secs != new.conversation_ttl_secs
+            || old.conversation_max_entries != new.conversation_max_entries,
+    );
+    check(
+        "ACCESS_TOKEN_REFRESH_MARGIN_SECS",
+        old.access_token_refresh_margin_secs != new.access_token_refresh_margin_secs,
+    );
+    check("CREDENTIALS_FILE", old.credentials_file != new.credentials_file);
+    changed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http_client::build_upstream_client;
+    use crate::metrics::Metrics;
+    use std::collections::HashMap;
+
+    /// 只从配置文件加载，测试结果不受进程环境变量和 `.env` 影响
+    fn load(cli: &CliArgs) -> Result<AppConfig> {
+        AppConfig::load_with_env(cli, HashMap::new())
+    }
+
+    fn write_config(path: &std::path::Path, extra: &str) {
+        let credentials_file = path.with_file_name("credentials.json");
+        let contents = format!(
+            "chatgpt_session_token = \"session\"\nchatgpt_authorization = \"auth\"\ncredentials_file = \"{}\"\n{}",
+            credentials_file.display(),
+            extra
+        );
+        std::fs::write(path, contents).unwrap();
+    }
+
+    async fn reloader(path: &std::path::Path) -> (ConfigReloader, SharedState) {
+        let cli = CliArgs { config_file: Some(path.to_path_buf()), ..Default::default() };
+        let config = Arc::new(load(&cli).unwrap());
+        let client = build_upstream_client(&config).unwrap();
+        let credentials = Arc::new(CredentialsStore::load(&config));
+        let tokens = Arc::new(
+            AccessTokenCache::new(credentials.clone(), client.clone(), Arc::new(Metrics::new().unwrap()), Duration::ZERO)
+                .await,
+        );
+        let conversations = Arc::new(ConversationStore::new(Duration::from_secs(60), 10));
+        let (mut reloader, state) = ConfigReloader::new(cli, config, client, credentials, tokens, conversations).unwrap();
+        reloader.load = load;
+        (reloader, state)
+    }
+
+    #[tokio::test]
+    async fn invalid_config_keeps_the_current_state() {
+        let dir = std::env::temp_dir().join(format!("chatgpt-proxy-reload-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config.toml");
+        write_config(&path, "max_requests_per_minute = 10\n");
+        let (reloader, state) = reloader(&path).await;
+        let before = state.current();
+
+        write_config(&path, "max_requests_per_minute = 0\n");
+        assert!(reloader.reload().await.is_err());
+        assert!(Arc::ptr_eq(&state.current(), &before));
+
+        write_config(&path, "max_requests_per_minute = 20\n");
+        reloader.reload().await.unwrap();
+        assert_eq!(state.current().config.max_requests_per_minute, 20);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn port_and_proxy_changes_require_a_restart() {
+        let dir = std::env::temp_dir().join(format!("chatgpt-proxy-restart-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config.toml");
+        write_config(&path, "");
+        let old = load(&CliArgs { config_file: Some(path), ..Default::default() }).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(restart_required(&old, &old.clone()).is_empty());
+
+        let mut new = old.clone();
+        new.server_port = old.server_port + 1;
+        new.all_proxy = Some("socks5h://127.0.0.1:1080".to_string());
+        new.max_requests_per_minute = old.max_requests_per_minute + 1;
+        assert_eq!(restart_required(&old, &new), ["SERVER_PORT", "Outbound proxy settings"]);
+
+        let mut new = old.clone();
+        new.no_proxy.push("internal.example".to_string());
+        assert_eq!(restart_required(&old, &new), ["Outbound proxy settings"]);
+    }
+}
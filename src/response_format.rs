@@ -0,0 +1,179 @@
+use jsonschema::Validator;
+use serde_json::Value;
+
+use crate::error::{ProxyError, ProxyResult};
+use crate::openai_types::{ChatCompletionRequest, Message, ResponseFormat};
+
+/// 一次校验中最多报告的Schema错误数，过多的错误对修正没有帮助
+const MAX_REPORTED_ERRORS: usize = 5;
+
+/// 请求要求的JSON输出格式
+pub struct JsonFormat {
+    // None表示只要求是JSON对象（json_object）
+    validator: Option<Validator>,
+}
+
+/// 把 `response_format` 改写为开头的system消息，要求JSON输出时返回用于校验回复的格式
+///
+/// 上游不支持该参数，代理只能在提示词中说明格式，并在收到回复后校验。
+pub fn prepare(req: &mut ChatCompletionRequest) -> ProxyResult<Option<JsonFormat>> {
+    let (instructions, validator) = match req.response_format.take() {
+        None | Some(ResponseFormat::Text) => return Ok(None),
+        Some(ResponseFormat::JsonObject) => (
+            "Reply with a single valid JSON object only. Do not wrap it in Markdown code fences \
+             and do not add any text before or after it."
+                .to_string(),
+            None,
+        ),
+        Some(ResponseFormat::JsonSchema { json_schema }) => {
+            let schema = json_schema.schema.ok_or_else(|| {
+                ProxyError::BadRequest("'response_format.json_schema.schema' is required".to_string())
+            })?;
+            let validator = jsonschema::validator_for(&schema).map_err(|e| {
+                ProxyError::BadRequest(format!("Invalid JSON Schema in 'response_format': {}", e))
+            })?;
+            let description = json_schema.description.map(|d| format!(" ({})", d)).unwrap_or_default();
+            (
+                format!(
+                    "Reply with a single valid JSON value only. Do not wrap it in Markdown code fences \
+                     and do not add any text before or after it. The JSON must conform to the JSON Schema \
+                     `{}`{}:\n{}",
+                    json_schema.name,
+                    description,
+                    serde_json::to_string_pretty(&schema).unwrap_or_default()
+                ),
+                Some(validator),
+            )
+        }
+    };
+
+    req.messages.insert(0, Message::text("system", instructions));
+    Ok(Some(JsonFormat { validator }))
+}
+
+impl JsonFormat {
+    /// 从回复中提取JSON并校验，成功时返回提取出的JSON文本，失败时返回交给上游修正的原因
+    pub fn check(&self, reply: &str) -> Result<String, String> {
+        let (json, value) = extract_json(reply).ok_or_else(|| "the reply does not contain valid JSON".to_string())?;
+
+        match &self.validator {
+            None if !value.is_object() => Err("the reply must be a JSON object".to_string()),
+            None => Ok(json.to_string()),
+            Some(validator) => {
+                let errors: Vec<_> = validator
+                    .iter_errors(&value)
+                    .take(MAX_REPORTED_ERRORS)
+                    .map(|e| match e.instance_path.as_str() {
+                        "" => e.to_string(),
+                        path => format!("{} (at {})", e, path),
+                    })
+                    .collect();
+                if errors.is_empty() {
+                    Ok(json.to_string())
+                } else {
+                    Err(format!("the JSON does not match the schema: {}", errors.join("; ")))
+                }
+            }
+        }
+    }
+
+    /// 校验失败后追加给上游的修正提示
+    pub fn repair_prompt(&self, reason: &str) -> String {
+        format!(
+            "Your previous reply could not be used: {}. Reply again with only the corrected JSON, \
+             without any explanation.",
+            reason
+        )
+    }
+}
+
+/// 提取回复中的JSON：整个回复、Markdown代码块，或第一个 `{`/`[` 到最后一个 `}`/`]` 之间的内容
+fn extract_json(reply: &str) -> Option<(&str, Value)> {
+    let parse = |text: &str| serde_json::from_str::<Value>(text).ok();
+    let trimmed = reply.trim();
+    if let Some(value) = parse(trimmed) {
+        return Some((trimmed, value));
+    }
+
+    // ```json ... ``` 代码块，跳过开头一行中的语言标记
+    if let Some((_, rest)) = trimmed.split_once("```") {
+        let body = rest.split_once('\n').map_or(rest, |(_, body)| body);
+        if let Some((block, _)) = body.split_once("```") {
+            let block = block.trim();
+            if let Some(value) = parse(block) {
+                return Some((block, value));
+            }
+        }
+    }
+
+    let start = trimmed.find(['{', '['])?;
+    let close = if trimmed[start..].starts_with('{') { '}' } else { ']' };
+    let end = trimmed.rfind(close)?;
+    let candidate = trimmed.get(start..=end)?;
+    parse(candidate).map(|value| (candidate, value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(response_format: Value) -> ChatCompletionRequest {
+        serde_json::from_value(serde_json::json!({
+            "model": "gpt-4o",
+            "messages": [{ "role": "user", "content": "List two colors" }],
+            "response_format": response_format,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn extracts_json_from_fences_and_prose() {
+        let fenced = "Here you go:\n```json\n{\"colors\": [\"red\"]}\n```\nAnything else?";
+        assert_eq!(extract_json(fenced).unwrap().0, r#"{"colors": ["red"]}"#);
+
+        let prose = r#"Sure! {"colors": ["red", "blue"]} Hope this helps."#;
+        assert_eq!(extract_json(prose).unwrap().0, r#"{"colors": ["red", "blue"]}"#);
+
+        assert!(extract_json("no json here").is_none());
+    }
+
+    #[test]
+    fn json_object_requires_an_object() {
+        let mut req = request(serde_json::json!({ "type": "json_object" }));
+        let format = prepare(&mut req).unwrap().unwrap();
+
+        assert_eq!(req.messages[0].role, "system");
+        assert!(format.check(r#"{"a": 1}"#).is_ok());
+        assert!(format.check("[1, 2]").is_err());
+    }
+
+    #[test]
+    fn schema_violations_are_reported() {
+        let mut req = request(serde_json::json!({
+            "type": "json_schema",
+            "json_schema": {
+                "name": "colors",
+                "schema": {
+                    "type": "object",
+                    "properties": { "colors": { "type": "array", "items": { "type": "string" } } },
+                    "required": ["colors"],
+                },
+            },
+        }));
+        let format = prepare(&mut req).unwrap().unwrap();
+
+        assert_eq!(format.check(r#"{"colors": ["red"]}"#).unwrap(), r#"{"colors": ["red"]}"#);
+        let reason = format.check(r#"{"colors": [1]}"#).unwrap_err();
+        assert!(reason.contains("/colors/0"), "{}", reason);
+        assert!(format.check(r#"{"shades": []}"#).is_err());
+    }
+
+    #[test]
+    fn invalid_schema_is_a_bad_request() {
+        let mut req = request(serde_json::json!({
+            "type": "json_schema",
+            "json_schema": { "name": "broken", "schema": { "type": "not-a-type" } },
+        }));
+        assert!(matches!(prepare(&mut req), Err(ProxyError::BadRequest(_))));
+    }
+}
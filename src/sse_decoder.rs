@@ -0,0 +1,180 @@
+use std::collections::HashMap;
+
+/// ChatGPT网页端事件流解码器
+///
+/// 上游每个 `data:` 事件都携带该消息截至目前的完整文本（`message.content.parts[0]`），
+/// 解码器按消息id记录上一次的文本，只输出新增的后缀。
+/// 数据可以按任意字节边界分块喂入，不完整的行会被缓存到下一次。
+#[derive(Debug, Default)]
+pub struct EventStreamDecoder {
+    buffer: Vec<u8>,
+    // 消息id -> 该消息上一次的完整文本
+    last_texts: HashMap<String, String>,
+    conversation_id: Option<String>,
+    assistant_message_id: Option<String>,
+    done: bool,
+}
+
+impl EventStreamDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 喂入一段上游数据，返回其中完整行解析出的增量文本
+    pub fn feed(&mut self, chunk: &[u8]) -> Vec<String> {
+        self.buffer.extend_from_slice(chunk);
+
+        let mut deltas = Vec::new();
+        while let Some(pos) = self.buffer.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = self.buffer.drain(..=pos).collect();
+            let line = String::from_utf8_lossy(&line);
+            if let Some(delta) = self.decode_line(line.trim_end()) {
+                deltas.push(delta);
+            }
+        }
+        deltas
+    }
+
+    /// 上游数据结束，处理缓冲区中最后一行没有换行符的数据
+    pub fn finish(&mut self) -> Vec<String> {
+        let rest = String::from_utf8_lossy(&self.buffer).to_string();
+        self.buffer.clear();
+        self.decode_line(rest.trim_end()).into_iter().collect()
+    }
+
+    /// 是否已收到 `data: [DONE]`
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+
+    /// 上游会话id
+    pub fn conversation_id(&self) -> Option<&str> {
+        self.conversation_id.as_deref()
+    }
+
+    /// 最后一条助手消息的id，续接会话时作为 parent_message_id
+    pub fn assistant_message_id(&self) -> Option<&str> {
+        self.assistant_message_id.as_deref()
+    }
+
+    /// 解码一行事件数据，返回新增的文本（如果有）
+    fn decode_line(&mut self, line: &str) -> Option<String> {
+        if self.done {
+            return None;
+        }
+
+        let data = line.strip_prefix("data:")?.trim_start();
+        if data == "[DONE]" {
+            self.done = true;
+            return None;
+        }
+
+        let json = serde_json::from_str::<serde_json::Value>(data).ok()?;
+        if let Some(conversation_id) = json.get("conversation_id").and_then(|c| c.as_str()) {
+            self.conversation_id = Some(conversation_id.to_string());
+        }
+        let message = json.get("message")?;
+
+        // 只输出助手的回复，忽略回显的用户消息、工具消息等
+        let role = message.pointer("/author/role").and_then(|r| r.as_str());
+        if role.is_some_and(|r| r != "assistant") {
+            return None;
+        }
+
+        let text = message.pointer("/content/parts/0").and_then(|p| p.as_str())?;
+        let message_id = message.get("id").and_then(|id| id.as_str()).unwrap_or_default();
+        self.assistant_message_id = Some(message_id.to_string());
+
+        let last_text = self.last_texts.entry(message_id.to_string()).or_default();
+        let delta = match text.strip_prefix(last_text.as_str()) {
+            Some(suffix) => suffix.to_string(),
+            // 文本被上游改写而不是追加，无法只输出后缀，整体作为增量
+            None => {
+                tracing::warn!("消息 {} 的文本不是上一次的延续，整体输出", message_id);
+                text.to_string()
+            }
+        };
+        *last_text = text.to_string();
+
+        if delta.is_empty() {
+            None
+        } else {
+            Some(delta)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASIC: &str = include_str!("../tests/fixtures/chatgpt_basic.txt");
+    const MULTI_MESSAGE: &str = include_str!("../tests/fixtures/chatgpt_multi_message.txt");
+    const UNICODE_CRLF: &str = include_str!("../tests/fixtures/chatgpt_unicode_crlf.txt");
+
+    fn decode_all(input: &[u8], chunk_size: usize) -> Vec<String> {
+        let mut decoder = EventStreamDecoder::new();
+        let mut deltas = Vec::new();
+        for chunk in input.chunks(chunk_size) {
+            deltas.extend(decoder.feed(chunk));
+        }
+        deltas.extend(decoder.finish());
+        deltas
+    }
+
+    #[test]
+    fn yields_only_new_suffix_of_cumulative_parts() {
+        let deltas = decode_all(BASIC.as_bytes(), BASIC.len());
+        assert_eq!(deltas, vec!["Hello", "! How", " can I", " help you today?"]);
+        assert_eq!(deltas.concat(), "Hello! How can I help you today?");
+    }
+
+    #[test]
+    fn stops_at_done_marker() {
+        let mut decoder = EventStreamDecoder::new();
+        decoder.feed(BASIC.as_bytes());
+        assert!(decoder.is_done());
+
+        let late = br#"data: {"message": {"id": "late", "author": {"role": "assistant"}, "content": {"parts": ["ignored"]}}}
+"#;
+        assert!(decoder.feed(late).is_empty());
+    }
+
+    #[test]
+    fn tracks_each_message_id_separately() {
+        let deltas = decode_all(MULTI_MESSAGE.as_bytes(), MULTI_MESSAGE.len());
+        assert_eq!(deltas.concat(), "Let me check.The answer is 42.");
+    }
+
+    #[test]
+    fn records_conversation_and_last_assistant_message_ids() {
+        let mut decoder = EventStreamDecoder::new();
+        decoder.feed(MULTI_MESSAGE.as_bytes());
+        assert_eq!(decoder.conversation_id(), Some("c0d3f1a2-6b1e-4c59-9a57-3f6f1f0e2b11"));
+        assert_eq!(decoder.assistant_message_id(), Some("5b0e8c44-1d7a-4a61-b1e3-0c9f0a7d2003"));
+    }
+
+    #[test]
+    fn handles_arbitrary_chunk_boundaries() {
+        for fixture in [BASIC, MULTI_MESSAGE, UNICODE_CRLF] {
+            let expected = decode_all(fixture.as_bytes(), fixture.len()).concat();
+            for chunk_size in [1, 3, 7, 64] {
+                assert_eq!(decode_all(fixture.as_bytes(), chunk_size).concat(), expected);
+            }
+        }
+    }
+
+    #[test]
+    fn decodes_multibyte_text_with_crlf_line_endings() {
+        let deltas = decode_all(UNICODE_CRLF.as_bytes(), 5);
+        assert_eq!(deltas.concat(), "你好！我是助手。");
+    }
+
+    #[test]
+    fn flushes_last_line_without_newline() {
+        let input = br#"data: {"message": {"id": "m1", "author": {"role": "assistant"}, "content": {"parts": ["tail"]}}}"#;
+        let mut decoder = EventStreamDecoder::new();
+        assert!(decoder.feed(input).is_empty());
+        assert_eq!(decoder.finish(), vec!["tail"]);
+    }
+}
@@ -0,0 +1,318 @@
+use anyhow::anyhow;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use reqwest::header;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::{Mutex, RwLock};
+
+use crate::credentials::{self, CredentialsStore, SESSION_COOKIE};
+use crate::error::{ProxyError, ProxyResult};
+use crate::http_client::UpstreamClient;
+use crate::metrics::SharedMetrics;
+
+const SESSION_URL: &str = "https://chat.openai.com/api/auth/session";
+
+/// 无法从JWT中解析出过期时间时，缓存的默认有效期
+const DEFAULT_TOKEN_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// 缓存的访问令牌
+#[derive(Debug, Clone)]
+struct CachedToken {
+    token: String,
+    expires_at: SystemTime,
+}
+
+/// 访问令牌缓存
+///
+/// 解析JWT的 `exp` 声明，在过期前 `refresh_margin` 之内一直返回缓存的令牌；
+/// 需要刷新时只有一个请求会访问 `/api/auth/session`，其余请求等待并复用其结果。
+pub struct AccessTokenCache {
+    credentials: Arc<CredentialsStore>,
+    client: UpstreamClient,
+    metrics: SharedMetrics,
+    cached: RwLock<Option<CachedToken>>,
+    refresh_lock: Mutex<()>,
+    refresh_margin: Duration,
+    session_url: String,
+}
+
+impl AccessTokenCache {
+    /// 创建缓存，配置中提供了JWT格式的访问令牌时直接作为初始值
+    pub async fn new(
+        credentials: Arc<CredentialsStore>,
+        client: UpstreamClient,
+        metrics: SharedMetrics,
+        refresh_margin: Duration,
+    ) -> Self {
+        let configured_token = parse_configured_token(&credentials.snapshot().await.authorization);
+        match &configured_token {
+            Some(token) => tracing::info!("Configured access token expires at {:?}", token.expires_at),
+            None => tracing::info!("No usable access token configured, will fetch one with the session token"),
+        }
+
+        Self {
+            credentials,
+            cached: RwLock::new(configured_token),
+            client,
+            metrics,
+            refresh_lock: Mutex::new(()),
+            refresh_margin,
+            session_url: SESSION_URL.to_string(),
+        }
+    }
+
+    /// 获取有效的访问令牌，必要时刷新
+    pub async fn get(&self) -> ProxyResult<String> {
+        if let Some(token) = self.fresh_token().await {
+            return Ok(token);
+        }
+
+        // 同一时间只允许一个刷新请求，拿到锁后再检查一次，其他任务可能已经刷新完成
+        let _guard = self.refresh_lock.lock().await;
+        if let Some(token) = self.fresh_token().await {
+            return Ok(token);
+        }
+        self.refresh_locked().await
+    }
+
+    /// 立即通过会话端点刷新，供后台任务定期调用，同时让上游轮换会话cookie
+    pub async fn refresh(&self) -> ProxyResult<()> {
+        let _guard = self.refresh_lock.lock().await;
+        self.refresh_locked().await?;
+        Ok(())
+    }
+
+    /// 认证信息重新加载后，丢弃缓存并重新使用配置中的访问令牌
+    pub async fn reset(&self) {
+        let configured_token = parse_configured_token(&self.credentials.snapshot().await.authorization);
+        *self.cached.write().await = configured_token;
+    }
+
+    /// 上游返回401时丢弃缓存，下次请求重新获取
+    pub async fn invalidate(&self) {
+        *self.cached.write().await = None;
+    }
+
+    /// 缓存中距过期还有 `refresh_margin` 以上的令牌
+    async fn fresh_token(&self) -> Option<String> {
+        self.cached
+            .read()
+            .await
+            .as_ref()
+            .filter(|token| is_valid_for(token, self.refresh_margin))
+            .map(|token| token.token.clone())
+    }
+
+    /// 通过会话端点获取新令牌并写入缓存，调用方必须持有 `refresh_lock`
+    async fn refresh_locked(&self) -> ProxyResult<String> {
+        tracing::info!("尝试使用会话令牌获取新的访问令牌");
+
+        let result = self.fetch_new_token().await;
+        self.metrics.record_token_refresh(result.is_ok());
+        let token = result?;
+
+        *self.cached.write().await = Some(token.clone());
+        Ok(token.token)
+    }
+
+    /// 从会话端点获取新令牌，失败时退回仍然有效的配置令牌
+    async fn fetch_new_token(&self) -> ProxyResult<CachedToken> {
+        let token = match self.fetch_from_session().await? {
+            Some(access_token) => {
+                let expires_at = jwt_expiry(&access_token).unwrap_or_else(|| SystemTime::now() + DEFAULT_TOKEN_TTL);
+                tracing::info!("成功获取访问令牌，过期时间 {:?}", expires_at);
+                CachedToken { token: access_token, expires_at }
+            }
+            // 会话端点没有返回令牌时，配置的令牌仍有效则继续使用
+            None => match parse_configured_token(&self.credentials.snapshot().await.authorization)
+                .filter(|t| is_valid_for(t, Duration::ZERO))
+            {
+                Some(token) => {
+                    tracing::warn!("无法获取新的访问令牌，继续使用配置的授权令牌");
+                    token
+                }
+                None => {
+                    tracing::warn!("请确保你的会话令牌是最新的，并且你已经登录到 chat.openai.com");
+                    return Err(ProxyError::AuthExpired(
+                        "Session token is no longer valid and no unexpired access token is configured".to_string(),
+                    ));
+                }
+            },
+        };
+        Ok(token)
+    }
+
+    /// 访问会话端点，返回其中的 accessToken（会话失效时上游返回不含令牌的JSON）
+    async fn fetch_from_session(&self) -> ProxyResult<Option<String>> {
+        // 设置会话Cookie
+        let cookie = format!("{}={}", SESSION_COOKIE, self.credentials.session_token().await);
+        let mut headers = header::HeaderMap::new();
+        headers.insert(
+            header::COOKIE,
+            header::HeaderValue::from_str(&cookie).map_err(|e| anyhow!("Invalid cookie value: {}", e))?
+        );
+        headers.insert(
+            header::USER_AGENT,
+            header::HeaderValue::from_static("Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/120.0.0.0 Safari/537.36")
+        );
+
+        tracing::debug!("请求 /api/auth/session 端点");
+        let resp = self.client.get(&self.session_url).headers(headers).send().await?;
+
+        let status = resp.status();
+        tracing::debug!("会话端点响应状态码: {}", status);
+
+        // 上游会通过 Set-Cookie 轮换会话令牌，保存新值
+        if let Some(session_token) = credentials::rotated_session_token(resp.headers()) {
+            self.credentials.update_session_token(session_token).await;
+        }
+
+        if !status.is_success() {
+            let error_text = resp.text().await.unwrap_or_default();
+            tracing::error!("获取访问令牌失败: 状态 {}, 内容: {}", status, error_text);
+            return Err(ProxyError::from_upstream_status(status, &error_text));
+        }
+
+        let json = resp.json::<serde_json::Value>().await.map_err(|e| {
+            tracing::error!("解析会话响应失败: {}", e);
+            ProxyError::Parse(format!("解析会话响应失败: {}", e))
+        })?;
+
+        Ok(json.get("accessToken").and_then(|t| t.as_str()).map(str::to_string))
+    }
+}
+
+/// 解析配置中的授权令牌，只接受JWT格式（可带 `Bearer ` 前缀）
+fn parse_configured_token(authorization: &str) -> Option<CachedToken> {
+    let token = authorization.trim_start_matches("Bearer ").trim();
+    if !token.starts_with("eyJ") {
+        return None;
+    }
+    let expires_at = jwt_expiry(token).unwrap_or_else(|| SystemTime::now() + DEFAULT_TOKEN_TTL);
+    Some(CachedToken { token: token.to_string(), expires_at })
+}
+
+/// 令牌在 `margin` 之后是否仍然有效
+fn is_valid_for(token: &CachedToken, margin: Duration) -> bool {
+    SystemTime::now() + margin < token.expires_at
+}
+
+/// 读取JWT payload中的 `exp` 声明（不校验签名）
+fn jwt_expiry(token: &str) -> Option<SystemTime> {
+    let payload = token.split('.').nth(1)?;
+    let bytes = URL_SAFE_NO_PAD.decode(payload.trim_end_matches('=')).ok()?;
+    let claims: serde_json::Value = serde_json::from_slice(&bytes).ok()?;
+    let exp = claims.get("exp")?.as_u64()?;
+    Some(UNIX_EPOCH + Duration::from_secs(exp))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{AppConfig, CliArgs};
+    use crate::metrics::Metrics;
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    fn jwt(claims: serde_json::Value) -> String {
+        format!("eyJhbGciOiJub25lIn0.{}.sig", URL_SAFE_NO_PAD.encode(claims.to_string()))
+    }
+
+    #[test]
+    fn expiry_is_read_from_the_exp_claim() {
+        let token = jwt(serde_json::json!({ "exp": 2_000_000_000u64 }));
+        assert_eq!(jwt_expiry(&token), Some(UNIX_EPOCH + Duration::from_secs(2_000_000_000)));
+
+        let configured = parse_configured_token(&format!("Bearer {}", token)).unwrap();
+        assert_eq!(configured.token, token);
+        assert_eq!(configured.expires_at, UNIX_EPOCH + Duration::from_secs(2_000_000_000));
+    }
+
+    #[test]
+    fn malformed_tokens_have_no_expiry() {
+        assert_eq!(jwt_expiry("not-a-jwt"), None);
+        assert_eq!(jwt_expiry("eyJhbGciOiJub25lIn0.!!!.sig"), None);
+        assert_eq!(jwt_expiry(&format!("eyJhbGciOiJub25lIn0.{}.sig", URL_SAFE_NO_PAD.encode("not json"))), None);
+        assert_eq!(jwt_expiry(&jwt(serde_json::json!({ "sub": "user" }))), None);
+
+        // 没有 exp 的JWT使用默认有效期，非JWT的授权值不会被缓存
+        let configured = parse_configured_token(&jwt(serde_json::json!({ "sub": "user" }))).unwrap();
+        assert!(is_valid_for(&configured, DEFAULT_TOKEN_TTL - Duration::from_secs(60)));
+        assert!(parse_configured_token("session-cookie-value").is_none());
+    }
+
+    /// 返回固定访问令牌的会话端点，记录收到的请求数
+    async fn session_endpoint(access_token: String, requests: Arc<AtomicUsize>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                requests.fetch_add(1, Ordering::SeqCst);
+                let body = serde_json::json!({ "accessToken": access_token }).to_string();
+                tokio::spawn(async move {
+                    let mut request = Vec::new();
+                    let mut buf = [0u8; 1024];
+                    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                        match stream.read(&mut buf).await {
+                            Ok(0) | Err(_) => return,
+                            Ok(n) => request.extend_from_slice(&buf[..n]),
+                        }
+                    }
+                    // 让其他调用方在刷新进行中到达
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    let response = format!(
+                        "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                        body.len(),
+                        body
+                    );
+                    let _ = stream.write_all(response.as_bytes()).await;
+                });
+            }
+        });
+        format!("http://{}/api/auth/session", addr)
+    }
+
+    #[tokio::test]
+    async fn concurrent_callers_share_one_refresh() {
+        // 配置只来自临时配置文件，不读取进程环境变量和 `.env`
+        let dir = std::env::temp_dir().join(format!("chatgpt-proxy-token-cache-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config.toml");
+        let contents = format!(
+            "chatgpt_session_token = \"session\"\nchatgpt_authorization = \"not-a-jwt\"\ncredentials_file = \"{}\"\n",
+            dir.join("credentials.json").display()
+        );
+        std::fs::write(&path, contents).unwrap();
+        let config = AppConfig::load_with_env(&CliArgs { config_file: Some(path), ..Default::default() }, HashMap::new())
+            .unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let access_token = jwt(serde_json::json!({ "exp": 4_000_000_000u64 }));
+        let requests = Arc::new(AtomicUsize::new(0));
+        let mut cache = AccessTokenCache::new(
+            Arc::new(CredentialsStore::load(&config)),
+            // 不使用系统代理，请求直接发到本地的会话端点
+            UpstreamClient::builder().no_proxy().build().unwrap(),
+            Arc::new(Metrics::new().unwrap()),
+            Duration::from_secs(60),
+        )
+        .await;
+        cache.session_url = session_endpoint(access_token.clone(), requests.clone()).await;
+
+        let cache = Arc::new(cache);
+        let callers = (0..8).map(|_| {
+            let cache = cache.clone();
+            tokio::spawn(async move { cache.get().await.unwrap() })
+        });
+        for token in futures::future::join_all(callers).await {
+            assert_eq!(token.unwrap(), access_token);
+        }
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+
+        // 缓存的令牌仍然有效，不会再次访问会话端点
+        assert_eq!(cache.get().await.unwrap(), access_token);
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+    }
+}
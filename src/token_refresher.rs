@@ -2,21 +2,20 @@ use std::sync::Arc;
 use std::time::{Duration, Instant};
 use tokio::sync::Mutex;
 use anyhow::Result;
-use reqwest::Client;
-use crate::config::AppConfig;
+use crate::token_cache::AccessTokenCache;
 
 /// TokenRefresher负责管理ChatGPT的token有效性
 pub struct TokenRefresher {
-    config: Arc<Mutex<AppConfig>>,
+    token_cache: Arc<AccessTokenCache>,
     last_check: Mutex<Instant>,
     check_interval: Duration,
 }
 
 impl TokenRefresher {
     /// 创建新的TokenRefresher实例
-    pub fn new(config: Arc<Mutex<AppConfig>>) -> Self {
+    pub fn new(token_cache: Arc<AccessTokenCache>) -> Self {
         Self {
-            config,
+            token_cache,
             last_check: Mutex::new(Instant::now()),
             // 默认每小时检查一次token有效性
             check_interval: Duration::from_secs(60 * 60),
@@ -58,83 +57,8 @@ impl TokenRefresher {
 
     /// 检查token有效性并在需要时刷新
     async fn check_and_refresh_tokens(&self) -> Result<()> {
-        // 获取当前配置
-        let config = {
-            let config_guard = self.config.lock().await;
-            config_guard.clone()
-        };
-        
-        // 检查token是否有效
-        if self.validate_tokens(&config).await? {
-            tracing::debug!("Tokens are still valid, no refresh needed");
-            return Ok(());
-        }
-        
-        // 如果token无效，尝试刷新
-        tracing::info!("Tokens need refresh, attempting to refresh...");
-        
-        // 这里实现token刷新逻辑，例如：
-        // 1. 使用已有凭证尝试获取新token
-        // 2. 使用预设的刷新方法
-        // 3. 从外部服务或配置获取新token
-        
-        // TODO: 实现实际的token刷新逻辑
-        // 这里是简化示例
-        let new_session_token = self.refresh_session_token(&config).await?;
-        let new_authorization = self.refresh_authorization(&config).await?;
-        
-        // 更新配置
-        {
-            let mut config_guard = self.config.lock().await;
-            config_guard.chatgpt_session_token = new_session_token;
-            config_guard.chatgpt_authorization = new_authorization;
-        }
-        
-        tracing::info!("Tokens refreshed successfully");
+        // 定期访问会话端点：刷新访问令牌，并保存上游轮换后的会话cookie
+        self.token_cache.refresh().await?;
         Ok(())
     }
-    
-    /// 验证token是否有效
-    async fn validate_tokens(&self, config: &AppConfig) -> Result<bool> {
-        // 创建HTTP客户端
-        let client = Client::new();
-        
-        // 简单测试API端点，通常是一个轻量级请求，仅用于验证token
-        let url = "https://chat.openai.com/api/auth/session";
-        
-        // 设置请求头
-        let response = client
-            .get(url)
-            .header("Cookie", format!("__Secure-next-auth.session-token={}", config.chatgpt_session_token))
-            .header("Authorization", &config.chatgpt_authorization)
-            .send()
-            .await?;
-            
-        // 检查响应状态
-        Ok(response.status().is_success())
-    }
-    
-    /// 刷新session token
-    async fn refresh_session_token(&self, _config: &AppConfig) -> Result<String> {
-        // 真实实现应该包含与OpenAI认证系统交互的逻辑
-        // 此处仅为占位代码
-        
-        // 通知用户当前需要手动刷新
-        tracing::warn!("Auto-refresh of session token not implemented yet. Please manually update it in .env file.");
-        
-        // 返回当前token，实际情况下应返回新token
-        Ok(_config.chatgpt_session_token.clone())
-    }
-    
-    /// 刷新authorization token
-    async fn refresh_authorization(&self, _config: &AppConfig) -> Result<String> {
-        // 真实实现应该包含获取新的Authorization token的逻辑
-        // 此处仅为占位代码
-        
-        // 通知用户当前需要手动刷新
-        tracing::warn!("Auto-refresh of authorization token not implemented yet. Please manually update it in .env file.");
-        
-        // 返回当前token，实际情况下应返回新token
-        Ok(_config.chatgpt_authorization.clone())
-    }
-}
\ No newline at end of file
+}
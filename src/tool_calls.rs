@@ -0,0 +1,273 @@
+use serde::Deserialize;
+use uuid::Uuid;
+
+use crate::error::{ProxyError, ProxyResult};
+use crate::openai_types::{ChatCompletionRequest, FunctionCall, Message, Tool, ToolCall, ToolChoice};
+use crate::output_limits::StopScanner;
+
+/// 回复中工具调用块的开始和结束标记
+const OPEN_TAG: &str = "<tool_calls>";
+const CLOSE_TAG: &str = "</tool_calls>";
+
+/// 本次请求可调用的工具，用于校验回复中的调用
+#[derive(Debug, Clone)]
+pub struct ToolSet {
+    names: Vec<String>,
+}
+
+/// 把工具调用相关的字段改写为普通消息，使任何后端都能处理
+///
+/// - assistant消息中的 `tool_calls` 按约定的格式追加到内容末尾
+/// - tool消息改写为带调用id的user消息
+/// - 启用了工具时，把工具说明作为开头的system消息，并返回可调用的工具
+pub fn prepare(req: &mut ChatCompletionRequest) -> ProxyResult<Option<ToolSet>> {
+    for message in &mut req.messages {
+        rewrite_history(message);
+    }
+
+    let tools = req.tools.take().unwrap_or_default();
+    if tools.is_empty() {
+        return Ok(None);
+    }
+    let all_names = || tools.iter().map(|t| t.function.name.clone()).collect::<Vec<_>>();
+
+    // 指定函数时只接受对该函数的调用
+    let (requirement, names) = match req.tool_choice.take() {
+        None => (String::new(), all_names()),
+        Some(ToolChoice::Mode(mode)) => match mode.as_str() {
+            "auto" => (String::new(), all_names()),
+            "none" => return Ok(None),
+            "required" => ("You must call at least one tool in this reply.".to_string(), all_names()),
+            other => return Err(ProxyError::BadRequest(format!("Unsupported tool_choice '{}'", other))),
+        },
+        Some(ToolChoice::Function { function }) => {
+            if !tools.iter().any(|t| t.function.name == function.name) {
+                return Err(ProxyError::BadRequest(format!(
+                    "tool_choice names '{}', which is not in 'tools'",
+                    function.name
+                )));
+            }
+            (format!("You must call the tool `{}` in this reply.", function.name), vec![function.name])
+        }
+    };
+
+    req.messages.insert(0, Message::text("system", instructions(&tools, &requirement)));
+    Ok(Some(ToolSet { names }))
+}
+
+/// 工具说明和调用格式
+fn instructions(tools: &[Tool], requirement: &str) -> String {
+    let definitions: Vec<_> = tools
+        .iter()
+        .map(|t| {
+            serde_json::json!({
+                "name": t.function.name,
+                "description": t.function.description,
+                "parameters": t.function.parameters,
+            })
+        })
+        .collect();
+
+    format!(
+        "You can call the following tools. Each tool has a name, a description and a JSON Schema for its arguments:\n\
+         {}\n\n\
+         To call tools, reply with only this block and nothing after it:\n\
+         {}[{{\"name\": \"tool_name\", \"arguments\": {{...}}}}]{}\n\
+         The array may contain several calls. Results are sent back in later messages starting with [Tool result]. \
+         If no tool is needed, answer normally without the block. {}",
+        serde_json::to_string_pretty(&definitions).unwrap_or_default(),
+        OPEN_TAG,
+        CLOSE_TAG,
+        requirement
+    )
+    .trim_end()
+    .to_string()
+}
+
+/// 把历史中的工具调用和工具结果改写为文本
+fn rewrite_history(message: &mut Message) {
+    if let Some(calls) = message.tool_calls.take() {
+        let rendered: Vec<_> = calls
+            .iter()
+            .map(|call| {
+                let arguments = serde_json::from_str::<serde_json::Value>(&call.function.arguments)
+                    .unwrap_or_else(|_| serde_json::Value::String(call.function.arguments.clone()));
+                serde_json::json!({ "name": call.function.name, "arguments": arguments })
+            })
+            .collect();
+        let block = format!("{}{}{}", OPEN_TAG, serde_json::Value::Array(rendered), CLOSE_TAG);
+        let text = message.content.text();
+        message.content = if text.is_empty() { block } else { format!("{}\n{}", text, block) }.into();
+    }
+
+    if message.role == "tool" || message.role == "function" {
+        let call = match (message.tool_call_id.take(), message.name.take()) {
+            (Some(id), Some(name)) => format!(" for {} ({})", id, name),
+            (Some(id), None) => format!(" for {}", id),
+            (None, Some(name)) => format!(" for {}", name),
+            (None, None) => String::new(),
+        };
+        message.role = "user".to_string();
+        message.content = format!("[Tool result{}]\n{}", call, message.content.text()).into();
+    }
+}
+
+/// 回复的工具调用部分
+#[derive(Debug, PartialEq)]
+pub enum Detected {
+    /// 回复中没有（有效的）工具调用，返回剩余的文本
+    Text(String),
+    ToolCalls(Vec<ToolCall>),
+}
+
+/// 在增量文本中识别工具调用块
+///
+/// 调用块之前的文本照常输出，遇到开始标记后缓存之后的全部内容，上游输出结束时再解析。
+pub struct ToolCallDetector {
+    tools: ToolSet,
+    scanner: StopScanner,
+    // 开始标记及之后的内容，None表示还没有遇到开始标记
+    block: Option<String>,
+}
+
+impl ToolCallDetector {
+    pub fn new(tools: ToolSet) -> Self {
+        Self {
+            tools,
+            scanner: StopScanner::new(vec![OPEN_TAG.to_string()]),
+            block: None,
+        }
+    }
+
+    /// 输入新增文本，返回工具调用块之前、可以直接输出的文本
+    pub fn push(&mut self, text: &str) -> String {
+        if let Some(block) = &mut self.block {
+            block.push_str(text);
+            return String::new();
+        }
+        let (output, found) = self.scanner.push(text);
+        if found {
+            self.block = Some(self.scanner.finish());
+        }
+        output
+    }
+
+    /// 上游输出结束，解析缓存的调用块；无法解析时作为普通文本返回
+    pub fn finish(&mut self) -> Detected {
+        match self.block.take() {
+            Some(block) => match parse_block(&block, &self.tools) {
+                Some(calls) => Detected::ToolCalls(calls),
+                None => Detected::Text(block),
+            },
+            None => Detected::Text(self.scanner.finish()),
+        }
+    }
+}
+
+/// 模型输出的单个调用
+#[derive(Debug, Deserialize)]
+struct RawCall {
+    name: String,
+    #[serde(default)]
+    arguments: serde_json::Value,
+}
+
+/// 解析以开始标记开头的调用块，调用了未提供的工具时视为无效
+fn parse_block(block: &str, tools: &ToolSet) -> Option<Vec<ToolCall>> {
+    let body = block.strip_prefix(OPEN_TAG)?;
+    let body = body.split(CLOSE_TAG).next().unwrap_or(body).trim();
+
+    let calls: Vec<RawCall> = serde_json::from_str(body)
+        .or_else(|_| serde_json::from_str::<RawCall>(body).map(|call| vec![call]))
+        .ok()?;
+    if calls.is_empty() || calls.iter().any(|call| !tools.names.contains(&call.name)) {
+        tracing::warn!("Ignoring tool call block with unknown tools: {}", body);
+        return None;
+    }
+
+    Some(
+        calls
+            .into_iter()
+            .map(|call| ToolCall {
+                id: format!("call_{}", Uuid::new_v4().simple()),
+                kind: "function".to_string(),
+                function: FunctionCall {
+                    name: call.name,
+                    // 参数按OpenAI格式以JSON字符串返回
+                    arguments: match call.arguments {
+                        serde_json::Value::String(arguments) => arguments,
+                        serde_json::Value::Null => "{}".to_string(),
+                        arguments => arguments.to_string(),
+                    },
+                },
+            })
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn weather_tools() -> ToolSet {
+        ToolSet {
+            names: vec!["get_weather".to_string()],
+        }
+    }
+
+    #[test]
+    fn detects_call_split_across_deltas() {
+        let mut detector = ToolCallDetector::new(weather_tools());
+
+        assert_eq!(detector.push("Let me check. <tool"), "Let me check. ");
+        assert_eq!(detector.push("_calls>[{\"name\": \"get_weather\", "), "");
+        assert_eq!(detector.push("\"arguments\": {\"city\": \"Paris\"}}]</tool_calls>"), "");
+
+        let Detected::ToolCalls(calls) = detector.finish() else {
+            panic!("expected tool calls");
+        };
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].function.name, "get_weather");
+        assert_eq!(calls[0].function.arguments, r#"{"city":"Paris"}"#);
+    }
+
+    #[test]
+    fn plain_reply_and_unknown_tools_stay_text() {
+        let mut detector = ToolCallDetector::new(weather_tools());
+        assert_eq!(detector.push("It is sunny."), "It is sunny.");
+        assert_eq!(detector.finish(), Detected::Text(String::new()));
+
+        let mut detector = ToolCallDetector::new(weather_tools());
+        let block = r#"<tool_calls>[{"name": "delete_files", "arguments": {}}]</tool_calls>"#;
+        assert_eq!(detector.push(block), "");
+        assert_eq!(detector.finish(), Detected::Text(block.to_string()));
+    }
+
+    #[test]
+    fn history_is_rewritten_as_text() {
+        let mut request: ChatCompletionRequest = serde_json::from_value(serde_json::json!({
+            "model": "gpt-4o",
+            "messages": [
+                { "role": "user", "content": "Weather in Paris?" },
+                { "role": "assistant", "content": null, "tool_calls": [{
+                    "id": "call_1", "type": "function",
+                    "function": { "name": "get_weather", "arguments": "{\"city\":\"Paris\"}" }
+                }]},
+                { "role": "tool", "tool_call_id": "call_1", "content": "18°C" },
+            ],
+            "tools": [{ "type": "function", "function": { "name": "get_weather" } }],
+        }))
+        .unwrap();
+
+        let tools = prepare(&mut request).unwrap();
+        assert!(tools.is_some());
+
+        let roles: Vec<_> = request.messages.iter().map(|m| m.role.as_str()).collect();
+        assert_eq!(roles, ["system", "user", "assistant", "user"]);
+        assert_eq!(
+            request.messages[2].content.text(),
+            r#"<tool_calls>[{"arguments":{"city":"Paris"},"name":"get_weather"}]</tool_calls>"#
+        );
+        assert_eq!(request.messages[3].content.text(), "[Tool result for call_1]\n18°C");
+    }
+}
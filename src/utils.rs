@@ -1,5 +1,13 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
 use crate::openai_types::ChatCompletionRequest;
 
+/// 估算时每个token对应的字符数
+pub const CHARS_PER_TOKEN: usize = 4;
+
+/// 每张图片按高清模式的典型开销估算
+const IMAGE_TOKENS: i64 = 765;
+
 /// 估算请求中的token数量（粗略估计）
 pub fn estimate_token_count(req: &ChatCompletionRequest) -> i64 {
     let mut tokens = 0;
@@ -9,8 +17,9 @@ pub fn estimate_token_count(req: &ChatCompletionRequest) -> i64 {
     
     for message in &req.messages {
         // 内容的token数：粗略估计为字符数/4（大约是英文单词的平均长度）
-        let content_tokens = message.content.chars().count() as i64 / 4;
-        tokens += message_tokens + content_tokens;
+        let content_tokens = (message.content.text().chars().count() / CHARS_PER_TOKEN) as i64;
+        let image_tokens = message.content.images().count() as i64 * IMAGE_TOKENS;
+        tokens += message_tokens + content_tokens + image_tokens;
     }
     
     // 考虑请求的基础token数
@@ -22,5 +31,44 @@ pub fn estimate_token_count(req: &ChatCompletionRequest) -> i64 {
 /// 估算字符串内容的token数量（粗略估计）
 pub fn estimate_token_count_str(text: &str) -> i64 {
     // 粗略估计为字符数/4
-    text.chars().count() as i64 / 4
+    (text.chars().count() / CHARS_PER_TOKEN) as i64
+}
+
+/// 返回UTC日期序号（自1970-01-01起的天数）和月份序号（年 * 12 + 月 - 1），用于按自然日/自然月统计
+pub fn utc_day_and_month(time: SystemTime) -> (u64, u32) {
+    let day = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() / 86_400;
+
+    // 公历日期换算（Howard Hinnant 的 civil_from_days 算法）
+    let z = day as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    (day, (year * 12 + month - 1) as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn month_index_follows_the_utc_calendar() {
+        // 1970-01-01
+        assert_eq!(utc_day_and_month(at(0)), (0, 1970 * 12));
+        // 2024-02-29 23:59:59 与 2024-03-01 00:00:00
+        assert_eq!(utc_day_and_month(at(1_709_251_199)), (19_782, 2024 * 12 + 1));
+        assert_eq!(utc_day_and_month(at(1_709_251_200)), (19_783, 2024 * 12 + 2));
+        // 2025-12-31 与 2026-01-01
+        assert_eq!(utc_day_and_month(at(1_767_139_200)).1, 2025 * 12 + 11);
+        assert_eq!(utc_day_and_month(at(1_767_225_600)).1, 2026 * 12);
+    }
 }